@@ -0,0 +1,114 @@
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use std::collections::HashMap;
+
+
+// ------- Structs ------- //
+
+
+/// Users allowed to authenticate, loaded from a file of `username:hash` lines.
+/// Hashes are argon2 PHC strings (`$argon2id$...`) or bcrypt hashes (`$2b$...`).
+pub struct CredentialStore {
+    users: HashMap<String, String>,
+    dummy_hash: Option<String>,
+}
+
+
+// ------- Implementations ------- //
+
+
+impl CredentialStore {
+    /// Load and validate the credentials file, failing on any malformed entry.
+    /// Blank lines and lines starting with `#` are ignored.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read credentials file {}: {}", path, e))?;
+
+        let mut users = HashMap::new();
+        let mut dummy_hash = None;
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((username, hash)) = line.split_once(':') else {
+                return Err(format!("{} line {} must be in the format username:hash", path, number + 1));
+            };
+            if username.is_empty() {
+                return Err(format!("{} line {} has an empty username", path, number + 1));
+            }
+            if !is_supported_hash(hash) {
+                return Err(format!("{} line {} must use an argon2 or bcrypt password hash", path, number + 1));
+            }
+            dummy_hash.get_or_insert_with(|| hash.to_string());
+            if users.insert(username.to_string(), hash.to_string()).is_some() {
+                return Err(format!("{} line {} repeats the user {}", path, number + 1, username));
+            }
+        }
+
+        log::info!("Loaded {} users for SMTP AUTH from: {}", users.len(), path);
+        Ok(CredentialStore { users, dummy_hash })
+    }
+
+    /// Check a username and password against the store.
+    /// Hashing is deliberately slow, so this should not run on the async executor.
+    /// An unknown user is checked against the hash of the first user in the file, which has the same cost
+    /// as the others, so that the time taken does not reveal whether the username exists.
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        match (self.users.get(username), &self.dummy_hash) {
+            (Some(hash), _) => verify_password(hash, password),
+            (None, Some(dummy_hash)) => {
+                verify_password(dummy_hash, password);
+                false
+            }
+            (None, None) => false,
+        }
+    }
+}
+
+
+// ------- Helpers ------- //
+
+
+/// Returns true if the hash is an argon2 PHC string or a bcrypt hash that can be parsed
+fn is_supported_hash(hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).is_ok()
+    } else {
+        hash.parse::<bcrypt::HashParts>().is_ok()
+    }
+}
+
+
+/// Verify a password against an argon2 or bcrypt hash
+fn verify_password(hash: &str, password: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash)
+            .is_ok_and(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+    } else {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+
+// ------- Tests ------- //
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_user_never_verifies() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let store = CredentialStore {
+            users: HashMap::from([("alice".to_string(), hash.clone())]),
+            dummy_hash: Some(hash),
+        };
+
+        assert!(store.verify("alice", "secret"));
+        assert!(!store.verify("alice", "wrong"));
+        // The password matches the hash used for unknown users, which must not authenticate them
+        assert!(!store.verify("mallory", "secret"));
+    }
+}
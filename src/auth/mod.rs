@@ -0,0 +1,92 @@
+use crate::models::configs::OAuthConfig;
+use self::credentials::CredentialStore;
+use self::oauth::{TokenIdentity, TokenValidator};
+use self::sasl::Mechanism;
+use std::sync::Arc;
+
+
+mod credentials;
+mod oauth;
+pub mod ownership;
+pub mod sasl;
+
+
+// ------- Structs ------- //
+
+
+/// Everything a client can authenticate against with SMTP AUTH: passwords, OAuth2 tokens or both
+pub struct Authenticator {
+    credentials: Option<CredentialStore>,
+    tokens: Option<TokenValidator>,
+    mechanisms: String,
+}
+
+
+// ------- Implementations ------- //
+
+
+impl Authenticator {
+    /// Load the configured credential sources, returning None when AUTH is not configured at all
+    pub fn load(users_file: Option<&str>, oauth: Option<&OAuthConfig>) -> Result<Option<Self>, String> {
+        let credentials = users_file.map(CredentialStore::load).transpose()?;
+        let tokens = oauth.map(TokenValidator::load).transpose()?;
+
+        let mut mechanisms = Vec::new();
+        if credentials.is_some() {
+            mechanisms.extend(["PLAIN", "LOGIN"]);
+        }
+        if tokens.is_some() {
+            mechanisms.extend(["XOAUTH2", "OAUTHBEARER"]);
+        }
+        if mechanisms.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Authenticator {
+            credentials,
+            tokens,
+            mechanisms: mechanisms.join(" "),
+        }))
+    }
+
+    /// The SASL mechanisms to advertise in the EHLO AUTH keyword
+    pub fn mechanisms(&self) -> &str {
+        &self.mechanisms
+    }
+
+    /// Returns true if the mechanism has a credential source configured
+    pub fn supports(&self, mechanism: &Mechanism) -> bool {
+        match mechanism {
+            Mechanism::Plain | Mechanism::Login => self.credentials.is_some(),
+            Mechanism::XOAuth2 | Mechanism::OAuthBearer => self.tokens.is_some(),
+        }
+    }
+
+    /// Check a username and password. Hashing is deliberately slow, so it runs on the blocking thread pool.
+    pub async fn verify_password(self: &Arc<Self>, username: &str, password: &str) -> bool {
+        let authenticator = Arc::clone(self);
+        let username = username.to_string();
+        let password = password.to_string();
+
+        tokio::task::spawn_blocking(move || {
+            authenticator
+                .credentials
+                .as_ref()
+                .is_some_and(|store| store.verify(&username, &password))
+        })
+        .await
+        .unwrap_or(false)
+    }
+
+    /// Validate an OAuth2 bearer token and return the identity it was issued for
+    pub fn verify_token(&self, token: &str) -> Option<TokenIdentity> {
+        let validator = self.tokens.as_ref()?;
+        match validator.validate(token) {
+            Ok(identity) => Some(identity),
+            Err(e) => {
+                log::debug!("Rejected OAuth2 token: {}", e);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header, jwk::JwkSet};
+use crate::models::configs::OAuthConfig;
+use serde::Deserialize;
+
+
+// ------- Structs ------- //
+
+
+/// Validates OAuth2 bearer tokens (JWTs) offline against a local JWKS file
+pub struct TokenValidator {
+    jwks: JwkSet,
+    issuer: String,
+    audience: String,
+}
+
+
+/// The claims of an access token used to identify the client
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    email: Option<String>,
+}
+
+
+/// The identity carried by a validated access token
+pub struct TokenIdentity {
+    pub subject: String,
+    pub email: Option<String>,
+}
+
+
+// ------- Implementations ------- //
+
+
+impl TokenValidator {
+    /// Load the JWKS file, failing if it can not be read or contains no usable keys
+    pub fn load(config: &OAuthConfig) -> Result<Self, String> {
+        let path = &config.jwks_file;
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read JWKS file {}: {}", path, e))?;
+        let jwks = serde_json::from_str::<JwkSet>(&contents)
+            .map_err(|e| format!("JWKS file {} must contain a valid JWKS document: {}", path, e))?;
+
+        if jwks.keys.is_empty() {
+            return Err(format!("JWKS file {} must contain at least one key", path));
+        }
+        for jwk in &jwks.keys {
+            DecodingKey::from_jwk(jwk)
+                .map_err(|e| format!("JWKS file {} contains a key that can not be used to verify tokens: {}", path, e))?;
+        }
+
+        log::info!("Loaded {} keys for OAuth2 token validation from: {}", jwks.keys.len(), path);
+        Ok(TokenValidator {
+            jwks,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+        })
+    }
+
+    /// Validate the signature, expiry, issuer and audience of a token and return its identity
+    pub fn validate(&self, token: &str) -> Result<TokenIdentity, jsonwebtoken::errors::Error> {
+        let header = decode_header(token)?;
+
+        // With a key ID the matching key is used, otherwise a JWKS with a single key is assumed
+        let jwk = match (&header.kid, self.jwks.keys.as_slice()) {
+            (Some(kid), _) => self.jwks.find(kid),
+            (None, [jwk]) => Some(jwk),
+            (None, _) => None,
+        }
+        .ok_or(jsonwebtoken::errors::ErrorKind::InvalidKeyFormat)?;
+
+        // A key pinned to an algorithm only verifies tokens signed with that algorithm
+        let algorithm = match jwk.common.key_algorithm {
+            Some(key_algorithm) => key_algorithm
+                .to_string()
+                .parse::<Algorithm>()
+                .map_err(|_| jsonwebtoken::errors::ErrorKind::InvalidAlgorithm)?,
+            None => header.alg,
+        };
+
+        let mut validation = Validation::new(algorithm);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+
+        let claims = decode::<Claims>(token, &DecodingKey::from_jwk(jwk)?, &validation)?.claims;
+        Ok(TokenIdentity { subject: claims.sub, email: claims.email })
+    }
+}
+
+
+impl TokenIdentity {
+    /// Returns true if the user named by the client is the subject, or the email, of the token
+    pub fn matches(&self, user: &str) -> bool {
+        self.subject == user || self.email.as_deref() == Some(user)
+    }
+}
@@ -0,0 +1,119 @@
+use crate::models::address::domain_to_ascii;
+use std::collections::HashMap;
+
+
+// ------- Structs ------- //
+
+
+/// Which sender addresses each authenticated user may use, loaded from a file of
+/// `username: address, @domain` lines. A `@domain` entry covers every address in that domain.
+/// Without an entry, a user may only send as the address equal to their username.
+#[derive(Default)]
+pub struct SenderOwnership {
+    owners: HashMap<String, Vec<String>>,
+}
+
+
+// ------- Implementations ------- //
+
+
+impl SenderOwnership {
+    /// Load and validate the ownership file, failing on any malformed entry.
+    /// Blank lines and lines starting with `#` are ignored.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read sender ownership file {}: {}", path, e))?;
+
+        let mut owners = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((username, addresses)) = line.split_once(':') else {
+                return Err(format!("{} line {} must be in the format username: address, @domain", path, number + 1));
+            };
+            let addresses = addresses
+                .split(',')
+                .map(str::trim)
+                .filter(|address| !address.is_empty())
+                .map(normalise_address)
+                .collect::<Option<Vec<_>>>();
+            let Some(addresses) = addresses.filter(|addresses| !addresses.is_empty() && !username.trim().is_empty()) else {
+                return Err(format!("{} line {} must list addresses or @domains with valid domains for a username", path, number + 1));
+            };
+
+            owners.entry(username.trim().to_string()).or_insert_with(Vec::new).extend(addresses);
+        }
+
+        log::info!("Loaded sender ownership for {} users from: {}", owners.len(), path);
+        Ok(SenderOwnership { owners })
+    }
+
+    /// Returns true if the authenticated user may send as the address.
+    /// Domains are compared as A-labels, so the address may use either form.
+    pub fn owns(&self, user: &str, address: &str) -> bool {
+        let address = normalise_address(address).unwrap_or_else(|| address.to_lowercase());
+
+        let Some(owned) = self.owners.get(user) else {
+            return Some(address) == normalise_address(user);
+        };
+
+        owned.iter().any(|owned| match owned.strip_prefix('@') {
+            Some(domain) => address.rsplit_once('@').is_some_and(|(_, d)| d == domain),
+            None => *owned == address,
+        })
+    }
+}
+
+
+// ------- Helpers ------- //
+
+
+/// Lowercase an address or `@domain` entry and convert its domain to A-labels,
+/// returning None if it has no `@` or an invalid domain
+fn normalise_address(address: &str) -> Option<String> {
+    let (local_part, domain) = address.rsplit_once('@')?;
+    let domain = domain_to_ascii(domain).ok()?;
+    Some(format!("{}@{}", local_part.to_lowercase(), domain))
+}
+
+
+// ------- Tests ------- //
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ownership(entries: &[(&str, &[&str])]) -> SenderOwnership {
+        let owners = entries
+            .iter()
+            .map(|(user, addresses)| (user.to_string(), addresses.iter().map(|a| normalise_address(a).unwrap()).collect()))
+            .collect();
+        SenderOwnership { owners }
+    }
+
+    #[test]
+    fn idn_sender() {
+        for entry in ["@bücher.de", "@xn--bcher-kva.de", "@BÜCHER.DE"] {
+            let ownership = ownership(&[("alice", &[entry])]);
+            assert!(ownership.owns("alice", "alice@xn--bcher-kva.de"), "{}", entry);
+            assert!(ownership.owns("alice", "Alice@bücher.de"), "{}", entry);
+            assert!(!ownership.owns("alice", "alice@buecher.de"), "{}", entry);
+        }
+
+        let ownership = ownership(&[("alice", &["info@bücher.de"])]);
+        assert!(ownership.owns("alice", "INFO@xn--bcher-kva.de"));
+        assert!(!ownership.owns("alice", "sales@bücher.de"));
+    }
+
+    #[test]
+    fn username_as_address() {
+        let ownership = ownership(&[]);
+        assert!(ownership.owns("bob@bücher.de", "bob@xn--bcher-kva.de"));
+        assert!(!ownership.owns("bob@bücher.de", "eve@bücher.de"));
+        assert!(!ownership.owns("bob", "bob@example.com"));
+    }
+}
@@ -0,0 +1,208 @@
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use crate::models::email::SMTPResponse;
+
+
+// ------- Constants ------- //
+
+
+// Longest AUTH command or client response line accepted, including the CRLF (RFC 4954 section 4)
+pub const MAX_RESPONSE_LINE_BYTES: usize = 12288;
+
+// Base64 encoded "Username:" and "Password:" prompts of the LOGIN mechanism
+pub const LOGIN_USERNAME_CHALLENGE: &str = "VXNlcm5hbWU6";
+pub const LOGIN_PASSWORD_CHALLENGE: &str = "UGFzc3dvcmQ6";
+
+// Base64 encoded {"status":"invalid_token","schemes":"bearer"} error sent when a token is rejected (RFC 7628 section 3.2.2)
+pub const OAUTH_ERROR_CHALLENGE: &str = "eyJzdGF0dXMiOiJpbnZhbGlkX3Rva2VuIiwic2NoZW1lcyI6ImJlYXJlciJ9";
+
+
+// ------- Enums ------- //
+
+
+/// SASL mechanisms offered by the AUTH command
+pub enum Mechanism {
+    Plain,          // RFC 4616
+    Login,          // draft-murchison-sasl-login
+    XOAuth2,        // Google and Microsoft OAuth2 mechanism
+    OAuthBearer,    // RFC 7628
+}
+
+
+// ------- Implementations ------- //
+
+
+impl Mechanism {
+    pub fn from_str(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "PLAIN" => Some(Mechanism::Plain),
+            "LOGIN" => Some(Mechanism::Login),
+            "XOAUTH2" => Some(Mechanism::XOAuth2),
+            "OAUTHBEARER" => Some(Mechanism::OAuthBearer),
+            _ => None,
+        }
+    }
+}
+
+
+// ------- Decoders ------- //
+
+
+/// Decode a base64 client response. A lone `*` cancels the exchange and a lone `=`
+/// stands for an empty initial response (RFC 4954 section 4).
+pub fn decode_response(response: &str) -> Result<Vec<u8>, SMTPResponse> {
+    match response.trim() {
+        "*" => Err(SMTPResponse::AuthCancelled),
+        "=" => Ok(Vec::new()),
+        encoded => BASE64.decode(encoded).map_err(|_| SMTPResponse::AuthInvalidResponse),
+    }
+}
+
+
+/// Decode a base64 client response that carries a single UTF-8 value, as LOGIN does
+pub fn decode_text(response: &str) -> Result<String, SMTPResponse> {
+    String::from_utf8(decode_response(response)?).map_err(|_| SMTPResponse::AuthInvalidResponse)
+}
+
+
+/// Split a decoded PLAIN message `authzid NUL authcid NUL passwd` into the username and password.
+/// Acting on behalf of another user is not supported, so an authzid must match the authcid.
+pub fn decode_plain(message: &[u8]) -> Result<(String, String), SMTPResponse> {
+    let message = std::str::from_utf8(message).map_err(|_| SMTPResponse::AuthInvalidResponse)?;
+
+    let mut parts = message.split('\0');
+    let (Some(authzid), Some(authcid), Some(password), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(SMTPResponse::AuthInvalidResponse);
+    };
+
+    if authcid.is_empty() || (!authzid.is_empty() && authzid != authcid) {
+        return Err(SMTPResponse::AuthFailed);
+    }
+
+    Ok((authcid.to_string(), password.to_string()))
+}
+
+
+/// Split a decoded XOAUTH2 message `user={user}^Aauth=Bearer {token}^A^A` into the user and token
+pub fn decode_xoauth2(message: &[u8]) -> Result<(Option<String>, String), SMTPResponse> {
+    let message = std::str::from_utf8(message).map_err(|_| SMTPResponse::AuthInvalidResponse)?;
+    let fields = message
+        .strip_suffix("\x01\x01")
+        .ok_or(SMTPResponse::AuthInvalidResponse)?;
+
+    let mut user = None;
+    let mut token = None;
+    for field in fields.split('\x01') {
+        match field.split_once('=') {
+            Some(("user", value)) => user = Some(value.to_string()),
+            Some(("auth", value)) => token = bearer_token(value),
+            _ => return Err(SMTPResponse::AuthInvalidResponse),
+        }
+    }
+
+    let token = token.ok_or(SMTPResponse::AuthInvalidResponse)?;
+    Ok((user.filter(|user| !user.is_empty()), token))
+}
+
+
+/// Split a decoded OAUTHBEARER message into the optional authzid and the token (RFC 7628 section 3.1).
+/// The message starts with a GS2 header such as `n,a=user@example.com,` followed by `^A` separated key/value pairs.
+pub fn decode_oauthbearer(message: &[u8]) -> Result<(Option<String>, String), SMTPResponse> {
+    let message = std::str::from_utf8(message).map_err(|_| SMTPResponse::AuthInvalidResponse)?;
+    let (gs2_header, pairs) = message
+        .split_once('\x01')
+        .ok_or(SMTPResponse::AuthInvalidResponse)?;
+
+    // Channel binding is not supported, so only the "n" and "y" flags are accepted
+    let mut header = gs2_header.split(',');
+    let (Some("n" | "y"), Some(authzid), Some(""), None) = (header.next(), header.next(), header.next(), header.next()) else {
+        return Err(SMTPResponse::AuthInvalidResponse);
+    };
+    let user = match authzid {
+        "" => None,
+        authzid => {
+            let name = authzid.strip_prefix("a=").ok_or(SMTPResponse::AuthInvalidResponse)?;
+            Some(name.replace("=2C", ",").replace("=3D", "=")).filter(|name| !name.is_empty())
+        }
+    };
+
+    let pairs = pairs.strip_suffix("\x01\x01").ok_or(SMTPResponse::AuthInvalidResponse)?;
+    let token = pairs
+        .split('\x01')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|&(key, _)| key == "auth")
+        .and_then(|(_, value)| bearer_token(value))
+        .ok_or(SMTPResponse::AuthInvalidResponse)?;
+
+    Ok((user, token))
+}
+
+
+/// The token of an `auth` value using the Bearer scheme, which is case insensitive (RFC 6750 section 2.1)
+fn bearer_token(value: &str) -> Option<String> {
+    let (scheme, token) = value.split_once(' ')?;
+    (scheme.eq_ignore_ascii_case("Bearer") && !token.is_empty()).then(|| token.to_string())
+}
+
+
+// ------- Tests ------- //
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responses() {
+        assert!(matches!(decode_response("dGVzdA=="), Ok(decoded) if decoded == b"test"));
+        assert!(matches!(decode_response("="), Ok(decoded) if decoded.is_empty()));
+        assert!(matches!(decode_response("*"), Err(SMTPResponse::AuthCancelled)));
+        assert!(matches!(decode_response("not base64!"), Err(SMTPResponse::AuthInvalidResponse)));
+    }
+
+    #[test]
+    fn login_text() {
+        assert!(matches!(decode_text("dXNlckBleGFtcGxlLmNvbQ=="), Ok(text) if text == "user@example.com"));
+        assert!(matches!(decode_text(&BASE64.encode([0xff, 0xfe])), Err(SMTPResponse::AuthInvalidResponse)));
+    }
+
+    #[test]
+    fn plain() {
+        assert!(matches!(decode_plain(b"\0user\0secret"), Ok((user, password)) if user == "user" && password == "secret"));
+        assert!(matches!(decode_plain(b"user\0user\0secret"), Ok((user, _)) if user == "user"));
+        assert!(matches!(decode_plain(b"user\0user\0"), Ok((_, password)) if password.is_empty()));
+
+        // Acting on behalf of another user and an empty username are refused
+        assert!(matches!(decode_plain(b"admin\0user\0secret"), Err(SMTPResponse::AuthFailed)));
+        assert!(matches!(decode_plain(b"\0\0secret"), Err(SMTPResponse::AuthFailed)));
+
+        assert!(matches!(decode_plain(b"user\0secret"), Err(SMTPResponse::AuthInvalidResponse)));
+        assert!(matches!(decode_plain(b"\0user\0secret\0more"), Err(SMTPResponse::AuthInvalidResponse)));
+        assert!(matches!(decode_plain(b"\0user\0\xff"), Err(SMTPResponse::AuthInvalidResponse)));
+    }
+
+    #[test]
+    fn xoauth2() {
+        let decoded = decode_xoauth2(b"user=user@example.com\x01auth=Bearer token\x01\x01");
+        assert!(matches!(decoded, Ok((Some(user), token)) if user == "user@example.com" && token == "token"));
+        assert!(matches!(decode_xoauth2(b"user=\x01auth=bearer token\x01\x01"), Ok((None, token)) if token == "token"));
+
+        assert!(matches!(decode_xoauth2(b"user=user@example.com\x01auth=Bearer token"), Err(SMTPResponse::AuthInvalidResponse)));
+        assert!(matches!(decode_xoauth2(b"user=user@example.com\x01auth=Basic token\x01\x01"), Err(SMTPResponse::AuthInvalidResponse)));
+        assert!(matches!(decode_xoauth2(b"user=user@example.com\x01\x01"), Err(SMTPResponse::AuthInvalidResponse)));
+        assert!(matches!(decode_xoauth2(b"user=user@example.com\x01other\x01\x01"), Err(SMTPResponse::AuthInvalidResponse)));
+    }
+
+    #[test]
+    fn oauthbearer() {
+        let decoded = decode_oauthbearer(b"n,a=user=2Cname=3D@example.com,\x01host=mx\x01port=587\x01auth=Bearer token\x01\x01");
+        assert!(matches!(decoded, Ok((Some(user), token)) if user == "user,name=@example.com" && token == "token"));
+        assert!(matches!(decode_oauthbearer(b"y,,\x01auth=Bearer token\x01\x01"), Ok((None, token)) if token == "token"));
+
+        // Channel binding is not supported
+        assert!(matches!(decode_oauthbearer(b"p=tls-unique,,\x01auth=Bearer token\x01\x01"), Err(SMTPResponse::AuthInvalidResponse)));
+        assert!(matches!(decode_oauthbearer(b"n,user,\x01auth=Bearer token\x01\x01"), Err(SMTPResponse::AuthInvalidResponse)));
+        assert!(matches!(decode_oauthbearer(b"n,,\x01auth=Bearer token\x01"), Err(SMTPResponse::AuthInvalidResponse)));
+        assert!(matches!(decode_oauthbearer(b"n,,\x01host=mx\x01\x01"), Err(SMTPResponse::AuthInvalidResponse)));
+        assert!(matches!(decode_oauthbearer(b"n,,auth=Bearer token\x01\x01"), Err(SMTPResponse::AuthInvalidResponse)));
+    }
+}
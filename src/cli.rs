@@ -0,0 +1,23 @@
+use clap::Parser;
+use log::LevelFilter;
+
+
+// ------- Structs ------- //
+
+
+/// Lightweight SMTP daemon that forwards received mail to an AMQP broker
+#[derive(Parser, Clone)]
+#[command(version, about)]
+pub struct Cli {
+    /// Configuration file to read, instead of CONFIG_FILE or /etc/lsmtpd/lsmtpd.toml
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<String>,
+
+    /// Validate the configuration, TLS material and AMQP reachability, then exit
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// Level of the daemon's own log messages (off, error, warn, info, debug or trace), overriding log_level
+    #[arg(short, long, value_name = "LEVEL")]
+    pub log_level: Option<LevelFilter>,
+}
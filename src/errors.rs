@@ -6,12 +6,32 @@ use std::error::Error;
 pub enum LSMTPError {
     IoError(std::io::Error),
     // AmqpError(amqprs::error::Error),
-    // TimeoutError,
-    InvalidEmailFormat,
+    TimeoutError,
+    // InvalidEmailFormat,
+    TooManyLineViolations,
+    SynchronizationError,
     // Other(String),
 }
 
 
+/// Why an email could not be published to the AMQP broker
+#[derive(Debug)]
+pub enum PublishError {
+    AmqpError(lapin::Error),
+    Nacked,             // The broker refused to take responsibility for the message
+    NotConfirmed,       // The channel is not in confirm mode, so delivery is unknown
+}
+
+
+/// Why the configuration could not be loaded at startup
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    Invalid(Vec<String>),
+}
+
+
 // ------- Implementations ------- //
 
 
@@ -20,14 +40,44 @@ impl fmt::Display for LSMTPError {
         match self {
             LSMTPError::IoError(e) => write!(f, "I/O Error: {}", e),
             // LSMTPError::AmqpError(e) => write!(f, "AMQP Error: {}", e),
-            // LSMTPError::TimeoutError => write!(f, "Operation timed out"),
-            LSMTPError::InvalidEmailFormat => write!(f, "Invalid email format"),
+            LSMTPError::TimeoutError => write!(f, "Operation timed out"),
+            // LSMTPError::InvalidEmailFormat => write!(f, "Invalid email format"),
+            LSMTPError::TooManyLineViolations => write!(f, "Too many overlong lines received"),
+            LSMTPError::SynchronizationError => write!(f, "Client sent commands out of synchronization"),
             // LSMTPError::Other(msg) => write!(f, "{}", msg),
         }
     }
 }
 
 
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PublishError::AmqpError(e) => write!(f, "AMQP Error: {}", e),
+            PublishError::Nacked => write!(f, "Message was nacked by the broker"),
+            PublishError::NotConfirmed => write!(f, "Message was not confirmed by the broker"),
+        }
+    }
+}
+
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "Failed to read configuration file {}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "Invalid configuration file {}: {}", path, e),
+            ConfigError::Invalid(problems) => {
+                write!(f, "Invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+
 impl Error for LSMTPError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
@@ -39,6 +89,41 @@ impl Error for LSMTPError {
 }
 
 
+impl Error for PublishError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PublishError::AmqpError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Read(_, e) => Some(e),
+            ConfigError::Parse(_, e) => Some(e),
+            ConfigError::Invalid(_) => None,
+        }
+    }
+}
+
+
+impl From<lapin::Error> for PublishError {
+    fn from(err: lapin::Error) -> Self {
+        PublishError::AmqpError(err)
+    }
+}
+
+
+impl From<String> for ConfigError {
+    fn from(problem: String) -> Self {
+        ConfigError::Invalid(vec![problem])
+    }
+}
+
+
 impl From<std::io::Error> for LSMTPError {
     fn from(err: std::io::Error) -> Self {
         LSMTPError::IoError(err)
@@ -1,46 +1,132 @@
-use tokio::net::{TcpStream, tcp::{OwnedReadHalf, OwnedWriteHalf}};
-use crate::models::email::{Email, SMTPCommand, SMTPResponse};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use crate::models::configs::MAX_EMAIL_SIZE_BYTES;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, ReadHalf, WriteHalf};
+use crate::models::email::{Email, SMTPCommand, SMTPResponse, SessionState};
+use crate::models::configs::Profile;
+use crate::auth::sasl::{self, Mechanism, MAX_RESPONSE_LINE_BYTES, LOGIN_USERNAME_CHALLENGE, LOGIN_PASSWORD_CHALLENGE, OAUTH_ERROR_CHALLENGE};
+use crate::auth::Authenticator;
 use crate::errors::LSMTPError;
+use crate::state::EmailSender;
+use crate::queue::{QueuedEmail, spool};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+use tokio::time;
+use tokio_rustls::TlsAcceptor;
+use super::policy::ListenerPolicy;
+use super::tls::SessionStream;
+
+
+// Failed AUTH attempts allowed before the connection is dropped
+const MAX_AUTH_FAILURES: usize = 3;
+
+
+/// A single line read from the client, kept as raw bytes without the line ending
+enum ReadLine {
+    Line(Vec<u8>),
+    TooLong,
+}
 
 
 /// Per-connection client object that owns the reader/writer and session state.
 pub struct EmailHandler {
     connection_id: uuid::Uuid,
-    reader: BufReader<OwnedReadHalf>,
-    writer: OwnedWriteHalf,
+    reader: BufReader<ReadHalf<SessionStream>>,
+    writer: BufWriter<WriteHalf<SessionStream>>,
+    policy: Arc<ListenerPolicy>,
+    tls_active: bool,
+    auth_failures: usize,
+    email_tx: EmailSender,
     email: Email,
-    data_mode: bool,
+    state: SessionState,
+    esmtp: bool,
+    data_size: usize,
+    data_line_too_long: bool,
+    line_violations: usize,
     buffer: Vec<u8>,
 }
 
 
 impl EmailHandler {
-    /// Create a EmailHandler from a connected TcpStream
-    pub fn new(socket: TcpStream, connection_id: uuid::Uuid) -> Self {
-        let (read_half, write_half) = socket.into_split();
+    /// Create a EmailHandler from a connected plaintext or TLS stream
+    pub fn new(stream: SessionStream, connection_id: uuid::Uuid, policy: Arc<ListenerPolicy>, email_tx: EmailSender) -> Self {
+        let tls_active = stream.is_tls();
+        let (read_half, write_half) = tokio::io::split(stream);
         let email_msg_id = uuid::Uuid::new_v4();
 
         log::info!("New LSMTP connection established. Connection ID: {}, Email Message ID: {}", connection_id, email_msg_id);
 
+        let mut email = Email::new(email_msg_id);
+        email.set_listener(policy.listener.name.clone(), policy.listener.routing_key.clone());
+
         EmailHandler {
             connection_id,
             reader: BufReader::new(read_half),
-            writer: write_half,
-            email: Email::new(email_msg_id),
-            data_mode: false,
+            writer: BufWriter::new(write_half),
+            policy,
+            tls_active,
+            auth_failures: 0,
+            email_tx,
+            email,
+            state: SessionState::Connected,
+            esmtp: false,
+            data_size: 0,
+            data_line_too_long: false,
+            line_violations: 0,
             buffer: Vec::with_capacity(1024),
         }
     }
 
-    async fn read_next_line(&mut self) -> Result<Option<String>, LSMTPError> {
+    /// Read the next line from the client, never buffering more than `limit` bytes of it.
+    /// An overlong line is consumed up to its newline and reported as `ReadLine::TooLong`.
+    async fn read_next_line(&mut self, limit: usize) -> Result<Option<ReadLine>, LSMTPError> {
         self.buffer.clear();
 
-        let bytes_read = self.reader.read_until(b'\n', &mut self.buffer).await?;
+        let mut too_long = false;
+
+        // Replies to a pipelined group are buffered, and sent together once all of it is processed
+        if self.reader.buffer().is_empty() {
+            self.writer.flush().await?;
+        }
+
+        loop {
+            // The session is dropped if the client stays idle for too long
+            let idle = time::Duration::from_secs(self.policy.listener.max_timeout_secs);
+            let Ok(available) = time::timeout(idle, self.reader.fill_buf()).await else {
+                return Err(self.idle_timeout().await);
+            };
+            let available = available?;
+
+            // End of stream, a partial line is still handed back like `read_until` would
+            if available.is_empty() {
+                if self.buffer.is_empty() && !too_long {
+                    return Ok(None);
+                }
+                break;
+            }
+
+            let (chunk_len, complete) = match available.iter().position(|&b| b == b'\n') {
+                Some(pos) => (pos + 1, true),
+                None => (available.len(), false),
+            };
+
+            // Once over the limit we only drain the rest of the line without keeping it
+            if !too_long {
+                if self.buffer.len() + chunk_len > limit {
+                    too_long = true;
+                    self.buffer.clear();
+                } else {
+                    self.buffer.extend_from_slice(&available[..chunk_len]);
+                }
+            }
+
+            self.reader.consume(chunk_len);
+
+            if complete {
+                break;
+            }
+        }
 
-        if bytes_read == 0 {
-            return Ok(None);
+        if too_long {
+            return Ok(Some(ReadLine::TooLong));
         }
 
         let line_bytes = self.buffer
@@ -48,7 +134,68 @@ impl EmailHandler {
             .or_else(|| self.buffer.strip_suffix(b"\n"))
             .unwrap_or(self.buffer.as_slice());
 
-        Ok(Some(String::from_utf8_lossy(line_bytes).into_owned()))
+        Ok(Some(ReadLine::Line(line_bytes.to_vec())))
+    }
+
+    /// Read exactly `size` octets of a BDAT chunk straight into the email, without any dot-unstuffing.
+    /// A chunk that is not kept, or that would go over the size limit, is only drained from the stream.
+    async fn read_chunk(&mut self, size: usize, keep: bool) -> Result<(), LSMTPError> {
+        let mut remaining = size;
+        if keep {
+            self.data_size = self.data_size.saturating_add(size);
+        }
+        let keep = keep && !self.data_size_exceeded();
+
+        while remaining > 0 {
+            let idle = time::Duration::from_secs(self.policy.listener.max_timeout_secs);
+            let Ok(available) = time::timeout(idle, self.reader.fill_buf()).await else {
+                return Err(self.idle_timeout().await);
+            };
+            let available = available?;
+
+            if available.is_empty() {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+
+            let chunk_len = available.len().min(remaining);
+            if keep {
+                self.email.add_content(&available[..chunk_len]);
+            }
+
+            self.reader.consume(chunk_len);
+            remaining -= chunk_len;
+        }
+
+        Ok(())
+    }
+
+    /// Close the session of a client that stayed idle for too long
+    async fn idle_timeout(&mut self) -> LSMTPError {
+        // Best effort, the client may already be gone
+        let _ = self.reply(SMTPResponse::Timeout).await;
+        let _ = self.writer.shutdown().await;
+        LSMTPError::TimeoutError
+    }
+
+    /// Record an overlong line, returning an error once the client has sent too many of them
+    async fn line_too_long(&mut self) -> Result<(), LSMTPError> {
+        self.line_violations += 1;
+        log::warn!("[conn={}] Received overlong line ({} of {} allowed)", self.connection_id, self.line_violations, self.policy.config.limits.max_line_violations);
+
+        if self.line_violations >= self.policy.config.limits.max_line_violations {
+            self.reply(SMTPResponse::TooManyErrors).await?;
+            self.writer.shutdown().await?;
+            return Err(LSMTPError::TooManyLineViolations);
+        }
+
+        // Within DATA the client is not reading replies, so the transaction is failed at the dot
+        if self.state == SessionState::Data {
+            self.data_line_too_long = true;
+        } else {
+            self.reply(SMTPResponse::LineTooLong).await?;
+        }
+
+        Ok(())
     }
 
     fn append_data_chunk(&mut self, line_bytes: &[u8]) {
@@ -58,73 +205,569 @@ impl EmailHandler {
             line_bytes
         };
 
+        // Count every byte of the transaction, but stop buffering once the limit is crossed.
+        // The rest of the message is still drained up to the terminating dot.
+        self.data_size += bytes.len() + 2;
+        if self.data_size_exceeded() {
+            return;
+        }
+
         self.email.add_content(bytes);
         self.email.add_content(b"\r\n");
     }
 
+    fn data_size_exceeded(&self) -> bool {
+        self.data_size > self.policy.listener.max_email_size
+    }
+
     async fn reply(&mut self, response: SMTPResponse) -> Result<(), LSMTPError> {
-        self.writer.write_all(&response.into_bytes()).await?;
+        self.writer.write_all(&response.into_bytes(&self.policy.config.server_name, self.esmtp)).await?;
         Ok(())
     }
 
-    /// Run the client session. Consumes self and returns the Email (or IO error).
-    pub async fn run(mut self) -> Result<Email, LSMTPError> {
+    /// Upgrade the session to TLS (RFC 3207), consuming self and returning the secured handler.
+    /// Any state gathered before the handshake, including the EHLO, is discarded.
+    async fn start_tls(self, acceptor: &TlsAcceptor) -> Result<Self, LSMTPError> {
+        // Anything the client pipelined after STARTTLS was sent in plaintext and must not be trusted
+        if !self.reader.buffer().is_empty() {
+            log::warn!("[conn={}] Discarding plaintext data received after STARTTLS", self.connection_id);
+        }
+
+        let stream = self.reader.into_inner().unsplit(self.writer.into_inner());
+        let stream = stream.upgrade(acceptor).await?;
+        let (read_half, write_half) = tokio::io::split(stream);
+
+        log::debug!("[conn={}] TLS handshake completed", self.connection_id);
+
+        let mut email = self.email;
+        email.reset();
+        email.set_client_address(String::new());
+        email.set_authenticated_user(None);
+
+        Ok(EmailHandler {
+            connection_id: self.connection_id,
+            reader: BufReader::new(read_half),
+            writer: BufWriter::new(write_half),
+            policy: self.policy,
+            tls_active: true,
+            auth_failures: self.auth_failures,
+            email_tx: self.email_tx,
+            email,
+            state: SessionState::Connected,
+            esmtp: false,
+            data_size: 0,
+            data_line_too_long: false,
+            line_violations: self.line_violations,
+            buffer: self.buffer,
+        })
+    }
+
+    /// Send a SASL challenge and wait for the client response line
+    async fn sasl_challenge(&mut self, challenge: &'static str) -> Result<String, LSMTPError> {
+        self.reply(SMTPResponse::AuthChallenge(challenge)).await?;
+
+        match self.read_next_line(MAX_RESPONSE_LINE_BYTES).await? {
+            Some(ReadLine::Line(line)) => Ok(String::from_utf8_lossy(&line).into_owned()),
+            // An overlong response can not be valid base64 for any credentials we would accept
+            Some(ReadLine::TooLong) => Ok(String::from("!")),
+            None => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+        }
+    }
+
+    /// Run the SASL exchange of an AUTH command (RFC 4954) and return the final reply to it.
+    /// On success the username, or the token subject, becomes the authenticated identity of the session.
+    async fn authenticate(&mut self, authenticator: Arc<Authenticator>, arg: &str) -> Result<SMTPResponse, LSMTPError> {
+        let (name, initial) = match arg.split_once(' ') {
+            Some((name, initial)) => (name, Some(initial.trim().to_string())),
+            None => (arg, None),
+        };
+
+        let Some(mechanism) = Mechanism::from_str(name).filter(|m| authenticator.supports(m)) else {
+            return Ok(SMTPResponse::AuthUnsupported);
+        };
+
+        let decoded = match mechanism {
+            Mechanism::XOAuth2 | Mechanism::OAuthBearer => {
+                let message = match initial {
+                    Some(initial) => initial,
+                    None => self.sasl_challenge("").await?,
+                };
+                let decoded = sasl::decode_response(&message).and_then(|message| match mechanism {
+                    Mechanism::XOAuth2 => sasl::decode_xoauth2(&message),
+                    _ => sasl::decode_oauthbearer(&message),
+                });
+                let (user, token) = match decoded {
+                    Ok(decoded) => decoded,
+                    Err(response) => return Ok(response),
+                };
+                return self.authenticate_token(&authenticator, user, &token).await;
+            }
+            Mechanism::Plain => {
+                let message = match initial {
+                    Some(initial) => initial,
+                    None => self.sasl_challenge("").await?,
+                };
+                sasl::decode_response(&message).and_then(|message| sasl::decode_plain(&message))
+            }
+            Mechanism::Login => {
+                let username = match initial {
+                    Some(initial) => initial,
+                    None => self.sasl_challenge(LOGIN_USERNAME_CHALLENGE).await?,
+                };
+                match sasl::decode_text(&username) {
+                    Ok(username) => {
+                        let password = self.sasl_challenge(LOGIN_PASSWORD_CHALLENGE).await?;
+                        sasl::decode_text(&password).map(|password| (username, password))
+                    }
+                    Err(response) => Err(response),
+                }
+            }
+        };
+
+        let (username, password) = match decoded {
+            Ok(decoded) => decoded,
+            Err(response) => return Ok(response),
+        };
+
+        if !authenticator.verify_password(&username, &password).await {
+            log::warn!("[conn={}] AUTH failed for user: {}", self.connection_id, username);
+            return Ok(SMTPResponse::AuthFailed);
+        }
+
+        log::info!("[conn={}] AUTH succeeded for user: {}", self.connection_id, username);
+        self.email.set_authenticated_user(Some(username));
+        Ok(SMTPResponse::AuthSuccess)
+    }
+
+    /// Complete an XOAUTH2 or OAUTHBEARER exchange by validating the bearer token.
+    /// A rejected token gets the JSON error challenge, and the client must answer it before the final failure (RFC 7628 section 3.2.3).
+    async fn authenticate_token(&mut self, authenticator: &Authenticator, user: Option<String>, token: &str) -> Result<SMTPResponse, LSMTPError> {
+        // The user named by the client, if any, must be the one the token was issued for
+        let identity = authenticator
+            .verify_token(token)
+            .filter(|identity| user.as_deref().is_none_or(|user| identity.matches(user)));
+
+        let Some(identity) = identity else {
+            log::warn!("[conn={}] AUTH failed for OAuth2 token of user: {}", self.connection_id, user.as_deref().unwrap_or("<none>"));
+            self.sasl_challenge(OAUTH_ERROR_CHALLENGE).await?;
+            return Ok(SMTPResponse::AuthFailed);
+        };
+
+        log::info!("[conn={}] AUTH succeeded for OAuth2 subject: {}", self.connection_id, identity.subject);
+        self.email.set_authenticated_user(Some(identity.subject));
+        Ok(SMTPResponse::AuthSuccess)
+    }
+
+    /// Check whether the client already sent data before the greeting banner (an early talker)
+    async fn is_early_talker(&mut self) -> Result<bool, LSMTPError> {
+        let delay = time::Duration::from_millis(self.policy.config.limits.greeting_delay_ms);
+        match time::timeout(delay, self.reader.fill_buf()).await {
+            Ok(available) => Ok(!available?.is_empty()),
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Reject a client that does not respect pipelining synchronisation points and close the session
+    async fn synchronization_error(&mut self) -> Result<(), LSMTPError> {
+        self.reply(SMTPResponse::SyncError).await?;
+        self.writer.shutdown().await?;
+        Err(LSMTPError::SynchronizationError)
+    }
+
+    /// Abort the current mail transaction, going back to the greeted state if there was one
+    fn reset_transaction(&mut self) {
+        self.email.reset();
+        self.data_size = 0;
+        self.data_line_too_long = false;
+
+        if self.state != SessionState::Connected {
+            self.state = SessionState::Greeted;
+        }
+    }
+
+    /// Complete the message once the terminating dot or the LAST BDAT chunk is received,
+    /// handing the email over for publishing and replying with the outcome of the transaction.
+    async fn end_data(&mut self) -> Result<(), LSMTPError> {
+        // A line in the message went over the text line limit, so the transaction is rejected
+        if self.data_line_too_long {
+            self.reset_transaction();
+            return self.reply(SMTPResponse::LineTooLong).await;
+        }
+
+        // The message went over the limit while reading DATA, so the transaction is rejected
+        if self.data_size_exceeded() {
+            log::warn!("[conn={}] Email rejected, DATA of {} bytes exceeds the maximum of {} bytes", self.connection_id, self.data_size, self.policy.listener.max_email_size);
+            self.reset_transaction();
+            return self.reply(SMTPResponse::SizeExceeded).await;
+        }
+
+        // Validate the transaction before taking responsibility for it
+        if let Err(e) = self.email.validate() {
+            log::warn!("[conn={}] Email validation failed: {}. Email summary: {}", self.connection_id, e, self.email.debug_summary());
+            self.reset_transaction();
+            return self.reply(SMTPResponse::TransactionFailed).await;
+        }
+
+        if self.policy.listener.profile == Profile::Submission {
+            // The authors in the From header must be owned by the user as well, when enforced
+            if self.policy.config.enforce_header_from {
+                let user = self.email.authenticated_user().unwrap_or_default();
+                let from = self.email.header_from();
+                if from.is_empty() || !from.iter().all(|author| self.policy.ownership.owns(user, author)) {
+                    log::warn!("[conn={}] User {} is not allowed to send as From: {:?}", self.connection_id, user, from);
+                    self.reset_transaction();
+                    return self.reply(SMTPResponse::NotAuthorized("From header is not owned by the authenticated user")).await;
+                }
+            }
+
+            self.email.complete_submission_headers(&self.policy.config.server_name);
+        }
+
+        // Hand the email over, stamped with the time it was accepted, and start a fresh transaction on the same session
+        self.email.set_received_now();
+        let email = self.email.take();
+        let message_id = email.message_id.clone();
+        self.reset_transaction();
+        log::info!("[conn={}] Email received successfully: {}", self.connection_id, email.debug_summary());
+
+        if !self.queue_email(email).await {
+            return self.reply(SMTPResponse::LocalError).await;
+        }
+
+        self.reply(SMTPResponse::DataEnd(message_id)).await
+    }
+
+    /// Hand the email to the AMQP publisher. With the durable spool it is first flushed to disk, so that
+    /// a crash cannot lose an email the client was told is queued. In durable mode this waits, up to the
+    /// confirm timeout, until the broker has confirmed it or it was saved to disk, so that a failure can still be
+    /// reported to the client with a 451 and the message retried. Returns false if the email was not taken.
+    async fn queue_email(&self, email: Email) -> bool {
+        let config = &self.policy.config;
+        let write_ahead = if config.durable_spool {
+            // Flushing to disk blocks, so it runs on the blocking thread pool
+            let (dir, message_id, payload) = (config.temp_email_dir.clone(), email.message_id.clone(), email.serialize());
+            let written = tokio::task::spawn_blocking(move || spool::write_ahead(&dir, &message_id, &payload))
+                .await
+                .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+            match written {
+                Ok(path) => Some(path),
+                Err(e) => {
+                    log::error!("[conn={}] Failed to write email {} to the spool: {}", self.connection_id, email.message_id, e);
+                    return false;
+                }
+            }
+        } else {
+            None
+        };
+
+        // Once handed over the publisher owns the write-ahead copy, until then a 451 must remove it
+        // so that it is not recovered and delivered on top of the client's retry
+        let message_id = email.message_id.clone();
+        let mut handed_over = false;
+        let queued = self.hand_over(email, write_ahead.clone(), &mut handed_over);
+        let queued = if config.amqp_details.wait_for_confirm {
+            time::timeout(time::Duration::from_secs(config.amqp_details.confirm_timeout_secs), queued).await
+        } else {
+            Ok(queued.await)
+        };
+
+        let taken = match queued {
+            Ok(taken) => taken,
+            Err(_) => {
+                log::error!("[conn={}] Email {} was not confirmed within {} seconds", self.connection_id, message_id, config.amqp_details.confirm_timeout_secs);
+                false
+            }
+        };
+        if !taken && !handed_over && let Some(path) = write_ahead {
+            spool::remove_write_ahead(&path);
+        }
+        taken
+    }
+
+    /// Send the email to the publisher, and in durable mode wait until it was stored.
+    /// An email still waiting in the channel when the session stops waiting is dropped by the publisher,
+    /// only one already being published when the wait times out may still go out.
+    async fn hand_over(&self, email: Email, write_ahead: Option<PathBuf>, handed_over: &mut bool) -> bool {
+        let message_id = email.message_id.clone();
+        let (stored_tx, stored_rx) = oneshot::channel();
+        let stored = self.policy.config.amqp_details.wait_for_confirm.then_some(stored_tx);
+        let waiting = stored.is_some();
+
+        if let Err(e) = self.email_tx.send(QueuedEmail { email, stored, write_ahead }).await {
+            log::error!("[conn={}] Failed to send email to AMQP channel: {}", self.connection_id, e);
+            return false;
+        }
+        *handed_over = true;
+
+        if waiting && !stored_rx.await.unwrap_or(false) {
+            log::error!("[conn={}] Email {} could not be published or saved locally", self.connection_id, message_id);
+            return false;
+        }
+        true
+    }
+
+    /// Run the client session until QUIT or idle timeout. Consumes self and publishes every
+    /// accepted Email to the AMQP channel as soon as its DATA completes.
+    pub async fn run(mut self) -> Result<(), LSMTPError> {
+        // Clients must wait for the greeting before talking
+        if self.is_early_talker().await? {
+            log::warn!("[conn={}] Client sent data before the greeting", self.connection_id);
+            return self.synchronization_error().await;
+        }
+
         // greet the client
-        self.reply(SMTPResponse::Greet).await?;
+        self.reply(SMTPResponse::Greet(self.policy.listener.banner.clone())).await?;
 
         loop {
-            let Some(line) = self.read_next_line().await? else {
+            // AUTH lines may be longer than other commands, so they are only checked against the command limit once parsed
+            let limit = if self.state == SessionState::Data {
+                self.policy.config.limits.max_text_line_bytes
+            } else {
+                self.policy.config.limits.max_command_line_bytes.max(MAX_RESPONSE_LINE_BYTES)
+            };
+
+            let Some(read) = self.read_next_line(limit).await? else {
                 self.writer.shutdown().await?;
                 break;
             };
-            let line_bytes = line.as_bytes();
+            let line_bytes = match read {
+                ReadLine::Line(line_bytes) => line_bytes,
+                ReadLine::TooLong => {
+                    self.line_too_long().await?;
+                    continue;
+                }
+            };
 
-            if self.data_mode {
+            // DATA lines stay as raw bytes so that 8BITMIME bodies in any charset are kept intact
+            if self.state == SessionState::Data {
                 if line_bytes == b"." {
-                    // end of DATA
-                    self.data_mode = false;
+                    self.end_data().await?;
                 } else {
                     self.append_data_chunk(&line_bytes);
-                    continue;
                 }
+                continue;
             }
 
-            // Not in data mode — parse command
-            match SMTPCommand::from_str(&line) {
+            // Commands are text, UTF-8 is allowed in them for SMTPUTF8 addresses
+            let Ok(line) = String::from_utf8(line_bytes) else {
+                self.reply(SMTPResponse::InvalidUtf8).await?;
+                continue;
+            };
+
+            // Not in data mode — parse command and check it is allowed in the current state
+            let command = SMTPCommand::from_str(&line);
+
+            if !matches!(command, SMTPCommand::Auth) && line.len() + 2 > self.policy.config.limits.max_command_line_bytes {
+                self.line_too_long().await?;
+                continue;
+            }
+
+            // More input already waiting means the client pipelined this command. That is only allowed
+            // after EHLO advertised PIPELINING, and never past a synchronisation point (RFC 2920).
+            if !self.reader.buffer().is_empty() && (!self.esmtp || command.is_sync_point()) {
+                log::warn!("[conn={}] Client pipelined commands without waiting for a reply to: {}", self.connection_id, line);
+                return self.synchronization_error().await;
+            }
+
+            match command {
                 SMTPCommand::HELO => {
                     // safely get argument after command: avoid direct slicing
                     let arg = line.get(5..).unwrap_or("").trim().to_string();
+                    // A new greeting implies a reset of any transaction in progress
+                    self.reset_transaction();
                     self.email.set_client_address(arg);
+                    self.state = SessionState::Greeted;
+                    self.esmtp = false;
                     self.reply(SMTPResponse::Helo).await?;
                 }
 
                 SMTPCommand::EHLO => {
                     let arg = line.get(5..).unwrap_or("").trim().to_string();
+                    self.reset_transaction();
                     self.email.set_client_address(arg);
-                    self.reply(SMTPResponse::Ehlo).await?;
+                    self.state = SessionState::Greeted;
+                    self.esmtp = true;
+                    let starttls = self.policy.starttls_acceptor().is_some() && !self.tls_active;
+                    let auth = self.policy.authenticator
+                        .as_ref()
+                        .filter(|_| self.tls_active)
+                        .map(|authenticator| authenticator.mechanisms().to_string());
+                    let max_size = self.policy.listener.max_email_size;
+                    self.reply(SMTPResponse::Ehlo { starttls, auth, max_size }).await?;
+                }
+
+                SMTPCommand::StartTls => {
+                    // TLS can only be started once, after EHLO and outside of a mail transaction
+                    if self.tls_active || self.state != SessionState::Greeted {
+                        self.reply(SMTPResponse::BadSequence).await?;
+                        continue;
+                    }
+
+                    let Some(acceptor) = self.policy.starttls_acceptor().cloned() else {
+                        self.reply(SMTPResponse::TlsNotAvailable).await?;
+                        continue;
+                    };
+
+                    self.reply(SMTPResponse::StartTls).await?;
+                    self.writer.flush().await?;
+                    self = self.start_tls(&acceptor).await?;
+                }
+
+                SMTPCommand::Auth => {
+                    let Some(authenticator) = self.policy.authenticator.clone() else {
+                        self.reply(SMTPResponse::NotImplemented).await?;
+                        continue;
+                    };
+
+                    // AUTH is only allowed once per session, after EHLO and outside of a mail transaction
+                    if !self.esmtp || self.state != SessionState::Greeted || self.email.authenticated_user().is_some() {
+                        self.reply(SMTPResponse::BadSequence).await?;
+                        continue;
+                    }
+
+                    // Passwords must never cross the wire in plaintext
+                    if !self.tls_active {
+                        self.reply(SMTPResponse::EncryptionRequired).await?;
+                        continue;
+                    }
+
+                    let arg = line.get(5..).unwrap_or("").trim().to_string();
+                    let response = self.authenticate(authenticator, &arg).await?;
+
+                    if matches!(response, SMTPResponse::AuthFailed) {
+                        self.auth_failures += 1;
+                        if self.auth_failures >= MAX_AUTH_FAILURES {
+                            log::warn!("[conn={}] Too many failed AUTH attempts, closing connection", self.connection_id);
+                            self.reply(SMTPResponse::TooManyErrors).await?;
+                            self.writer.shutdown().await?;
+                            break;
+                        }
+                    }
+                    self.reply(response).await?;
                 }
 
                 SMTPCommand::MailFrom => {
+                    // MAIL is only valid after a greeting and not inside another transaction
+                    if self.state != SessionState::Greeted {
+                        self.reply(SMTPResponse::BadSequence).await?;
+                        continue;
+                    }
+
+                    // Submission is only open to authenticated users (RFC 6409 section 4.3)
+                    let submitter = self.email.authenticated_user().map(str::to_string);
+                    if self.policy.listener.profile == Profile::Submission && submitter.is_none() {
+                        self.reply(SMTPResponse::AuthRequired).await?;
+                        continue;
+                    }
+
                     // safe slice: MAIL FROM: is 10 chars, but use get to avoid panic
                     let addr_part = line.get(10..).unwrap_or("").trim();
-                    let (sender, valid) = SMTPResponse::mail_from_response(addr_part, *MAX_EMAIL_SIZE_BYTES);
-                    if !valid {
-                        self.reply(SMTPResponse::SizeExceeded).await?;
+                    let mail_from = match SMTPResponse::mail_from_response(addr_part, self.policy.listener.max_email_size) {
+                        Ok(mail_from) => mail_from,
+                        Err(response) => {
+                            self.reply(response).await?;
+                            continue;
+                        }
+                    };
+
+                    // A submitted message may only be sent from an address the user owns
+                    if let Some(user) = submitter.filter(|_| self.policy.listener.profile == Profile::Submission)
+                        && !self.policy.ownership.owns(&user, &mail_from.sender)
+                    {
+                        log::warn!("[conn={}] User {} is not allowed to send as: <{}>", self.connection_id, user, mail_from.sender);
+                        self.reply(SMTPResponse::NotAuthorized("Sender address is not owned by the authenticated user")).await?;
                         continue;
                     }
-                    self.email.set_sender(sender);
+                    self.email.set_mail_from(mail_from);
+                    self.state = SessionState::Mail;
                     self.reply(SMTPResponse::Ok).await?;
                 }
 
                 SMTPCommand::RcptTo => {
-                    let arg = line.get(8..).unwrap_or("").trim().to_string();
-                    self.email.add_recipient(arg);
+                    if !matches!(self.state, SessionState::Mail | SessionState::Rcpt) {
+                        self.reply(SMTPResponse::BadSequence).await?;
+                        continue;
+                    }
+
+                    let arg = line.get(8..).unwrap_or("").trim();
+                    let recipient = match SMTPResponse::rcpt_to_response(arg, self.email.smtputf8(), &self.policy.config.server_name) {
+                        Ok(recipient) => recipient,
+                        Err(response) => {
+                            self.reply(response).await?;
+                            continue;
+                        }
+                    };
+
+                    // An MX listener only accepts mail for its own domains, anything else would be relaying
+                    let domain = recipient.address.rsplit_once('@').map_or("", |(_, domain)| domain);
+                    if !self.policy.accepts_recipient_domain(domain) {
+                        self.reply(SMTPResponse::NotAuthorized("Relaying denied")).await?;
+                        continue;
+                    }
+                    self.email.add_recipient(recipient);
+                    self.state = SessionState::Rcpt;
                     self.reply(SMTPResponse::Ok).await?;
                 }
 
                 SMTPCommand::Data => {
-                    self.reply(SMTPResponse::Data).await?;
-                    self.data_mode = true;
+                    match self.state {
+                        // A BINARYMIME body can only be sent with BDAT (RFC 3030 section 3)
+                        SessionState::Rcpt if self.email.binarymime() => self.reply(SMTPResponse::BadSequence).await?,
+
+                        SessionState::Rcpt => {
+                            self.reply(SMTPResponse::Data).await?;
+                            self.state = SessionState::Data;
+                            self.data_size = 0;
+                            self.data_line_too_long = false;
+                        }
+
+                        // A transaction was started but none of its recipients were accepted
+                        SessionState::Mail => self.reply(SMTPResponse::NoValidRecipients).await?,
+
+                        _ => self.reply(SMTPResponse::BadSequence).await?,
+                    }
+                }
+
+                SMTPCommand::Bdat => {
+                    let arg = line.get(5..).unwrap_or("").trim();
+                    let (size, last) = match SMTPResponse::bdat_response(arg) {
+                        Ok(bdat) => bdat,
+                        Err(response) => {
+                            // Without a valid size the chunk can not be skipped, so the session can not continue
+                            self.reply(response).await?;
+                            self.writer.shutdown().await?;
+                            break;
+                        }
+                    };
+
+                    // The first chunk starts a new message
+                    if self.state == SessionState::Rcpt {
+                        self.data_size = 0;
+                        self.data_line_too_long = false;
+                    }
+
+                    // The chunk always follows the command, so it is read even when the command is rejected
+                    let accepted = matches!(self.state, SessionState::Rcpt | SessionState::Bdat);
+                    self.read_chunk(size, accepted).await?;
+
+                    match self.state {
+                        SessionState::Rcpt | SessionState::Bdat if last => self.end_data().await?,
+
+                        SessionState::Rcpt | SessionState::Bdat if self.data_size_exceeded() => {
+                            log::warn!("[conn={}] Email rejected, BDAT of {} bytes exceeds the maximum of {} bytes", self.connection_id, self.data_size, self.policy.listener.max_email_size);
+                            self.reset_transaction();
+                            self.reply(SMTPResponse::SizeExceeded).await?;
+                        }
+
+                        SessionState::Rcpt | SessionState::Bdat => {
+                            self.state = SessionState::Bdat;
+                            self.reply(SMTPResponse::ChunkReceived(size)).await?;
+                        }
+
+                        SessionState::Mail => self.reply(SMTPResponse::NoValidRecipients).await?,
+
+                        _ => self.reply(SMTPResponse::BadSequence).await?,
+                    }
                 }
 
                 SMTPCommand::Quit => {
@@ -138,18 +781,13 @@ impl EmailHandler {
                 }
 
                 SMTPCommand::Dot => {
-                    self.reply(SMTPResponse::DataEnd(self.email.message_id.clone())).await?;
-                    self.data_mode = false;
-
-                    // We close the connection immediately after receiving the email data, as per typical SMTP behavior.
-                    self.writer.shutdown().await?;
-                    break;
+                    // A lone dot is only meaningful at the end of DATA
+                    self.reply(SMTPResponse::BadSequence).await?;
                 }
 
                 SMTPCommand::Reset => {
-                    self.email.reset();
+                    self.reset_transaction();
                     self.buffer.clear();
-                    self.data_mode = false;
                     self.reply(SMTPResponse::Ok).await?;
                 }
 
@@ -160,18 +798,7 @@ impl EmailHandler {
             }
         }
 
-        // Final validation
-        match self.email.validate() {
-            Ok(_) => {
-                log::info!("[conn={}] Email received successfully: {}", self.connection_id, self.email.debug_summary());
-                Ok(self.email)
-            }
-
-            Err(e) => {
-                log::warn!("[conn={}] Email validation failed: {}. Email summary: {}", self.connection_id, e, self.email.debug_summary());
-                self.writer.shutdown().await?;
-                Err(LSMTPError::InvalidEmailFormat)
-            }
-        }
+        log::debug!("[conn={}] Session closed", self.connection_id);
+        Ok(())
     }
 }
@@ -1 +1,3 @@
 pub mod email;
+pub mod policy;
+pub mod tls;
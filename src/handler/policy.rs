@@ -0,0 +1,47 @@
+use crate::models::configs::{BaseConfig, ListenerConfig, Profile};
+use crate::models::address::domain_to_ascii;
+use crate::auth::ownership::SenderOwnership;
+use crate::auth::Authenticator;
+use tokio_rustls::TlsAcceptor;
+use super::tls::TlsMode;
+use std::sync::Arc;
+
+
+// ------- Structs ------- //
+
+
+/// What a listener offers and enforces on every session it accepts
+pub struct ListenerPolicy {
+    pub config: Arc<BaseConfig>,
+    pub listener: ListenerConfig,
+    pub tls_mode: TlsMode,
+    pub authenticator: Option<Arc<Authenticator>>,
+    pub ownership: Arc<SenderOwnership>,
+}
+
+
+// ------- Implementations ------- //
+
+
+impl ListenerPolicy {
+    /// The acceptor used to upgrade plaintext sessions with STARTTLS, if offered
+    pub fn starttls_acceptor(&self) -> Option<&TlsAcceptor> {
+        match &self.tls_mode {
+            TlsMode::StartTls(acceptor) => Some(acceptor),
+            _ => None,
+        }
+    }
+
+    /// Returns true if a recipient in the domain may be accepted. Only MX listeners restrict recipients,
+    /// and the server name itself is always accepted so that <Postmaster> keeps working.
+    /// The domain is compared as A-labels, so it may be given in either form.
+    pub fn accepts_recipient_domain(&self, domain: &str) -> bool {
+        match (&self.config.accepted_domains, self.listener.profile) {
+            (Some(accepted), Profile::Mx) => {
+                let domain = domain_to_ascii(domain).unwrap_or_else(|_| domain.to_string());
+                domain.eq_ignore_ascii_case(&self.config.server_name) || accepted.iter().any(|d| d.eq_ignore_ascii_case(&domain))
+            }
+            _ => true,
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+use tokio_rustls::rustls::ServerConfig;
+use std::task::{Context, Poll};
+use tokio::net::TcpStream;
+use std::sync::Arc;
+use std::pin::Pin;
+use std::io;
+
+
+/// The underlying transport of a client session, either plaintext or TLS wrapped
+pub enum SessionStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+
+/// How TLS is offered on a listener
+#[derive(Clone)]
+pub enum TlsMode {
+    Disabled,                   // Plaintext only
+    StartTls(TlsAcceptor),      // Plaintext with an optional STARTTLS upgrade
+    Implicit(TlsAcceptor),      // TLS from the first byte (SMTPS)
+}
+
+
+// ------- Implementations ------- //
+
+
+impl SessionStream {
+    /// Returns true if the session is already running over TLS
+    pub fn is_tls(&self) -> bool {
+        matches!(self, SessionStream::Tls(_))
+    }
+
+
+    /// Upgrade a plaintext session to TLS, doing the server side handshake
+    pub async fn upgrade(self, acceptor: &TlsAcceptor) -> io::Result<Self> {
+        match self {
+            SessionStream::Plain(socket) => {
+                let tls = acceptor.accept(socket).await?;
+                Ok(SessionStream::Tls(Box::new(tls)))
+            }
+            SessionStream::Tls(_) => Err(io::Error::other("Session is already using TLS")),
+        }
+    }
+}
+
+
+impl AsyncRead for SessionStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SessionStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            SessionStream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+
+impl AsyncWrite for SessionStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            SessionStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            SessionStream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SessionStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            SessionStream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            SessionStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            SessionStream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+
+/// Build a TLS acceptor from PEM encoded certificate chain and private key files
+pub fn load_tls_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, String> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("TLS certificate {} must contain valid PEM certificates: {}", cert_path, e))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("TLS private key {} must contain a valid PEM private key: {}", key_path, e))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("TLS certificate and private key do not match or are invalid: {}", e))?;
+
+    log::info!("TLS enabled with certificate: {}", cert_path);
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
@@ -0,0 +1,94 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+// ------- Constants ------- //
+
+
+// Target prefix of the daemon's own log messages
+const DAEMON_TARGET: &str = "lsmtpd";
+
+// Stored in DAEMON_LEVEL while no log level is configured
+const NO_LEVEL: usize = usize::MAX;
+
+
+// ------- Structs ------- //
+
+
+/// Formats messages with env_logger and filters them with RUST_LOG, except that the configured
+/// log level, which can change on reload, decides which of the daemon's own messages are shown
+struct DaemonLogger {
+    filter: env_filter::Filter,
+    inner: env_logger::Logger,
+}
+
+
+// ------- Static Variables ------- //
+
+
+// The configured log level of the daemon's own messages, or NO_LEVEL to leave them to RUST_LOG
+static DAEMON_LEVEL: AtomicUsize = AtomicUsize::new(NO_LEVEL);
+
+// The most verbose level RUST_LOG enables for any module
+static RUST_LOG_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Error as usize);
+
+
+// ------- Implementations ------- //
+
+
+impl Log for DaemonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let target = metadata.target();
+        let is_daemon = target == DAEMON_TARGET || target.starts_with("lsmtpd::");
+
+        match DAEMON_LEVEL.load(Ordering::Relaxed) {
+            level if is_daemon && level != NO_LEVEL => metadata.level() as usize <= level,
+            _ => self.filter.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+
+// ------- Helpers ------- //
+
+
+/// Install the logger. RUST_LOG selects what is logged, errors only when unset, until a log level is configured.
+pub fn init() {
+    let filter = env_filter::Builder::from_env("RUST_LOG").build();
+    RUST_LOG_LEVEL.store(filter.filter() as usize, Ordering::Relaxed);
+
+    // Filtering is done by the DaemonLogger, so the inner logger lets everything through
+    let inner = env_logger::Builder::new().filter_level(LevelFilter::Trace).build();
+
+    log::set_boxed_logger(Box::new(DaemonLogger { filter, inner })).expect("Logger must only be initialised once");
+    log::set_max_level(filter_level());
+}
+
+
+/// Set the log level of the daemon's own messages, or leave them to RUST_LOG with None
+pub fn set_level(level: Option<LevelFilter>) {
+    DAEMON_LEVEL.store(level.map_or(NO_LEVEL, |level| level as usize), Ordering::Relaxed);
+    log::set_max_level(filter_level());
+}
+
+
+/// The most verbose level any message can be logged at, so that the log macros skip everything above it
+fn filter_level() -> LevelFilter {
+    let rust_log = RUST_LOG_LEVEL.load(Ordering::Relaxed);
+    let daemon = match DAEMON_LEVEL.load(Ordering::Relaxed) {
+        NO_LEVEL => 0,
+        level => level,
+    };
+
+    LevelFilter::iter().nth(rust_log.max(daemon)).unwrap_or(LevelFilter::Trace)
+}
@@ -1,27 +1,39 @@
+use clap::Parser;
+
+
+mod cli;
 mod handler;
+mod auth;
 mod models;
 mod errors;
+mod logging;
 mod queue;
 mod state;
 
 
 #[tokio::main]
 async fn main() -> Result<(), errors::LSMTPError> {
+    let cli = cli::Cli::parse();
+
+    // A dry run only validates the configuration and reports the result with the exit code
+    if cli.check_config {
+        let valid = state::check_config(&cli).await;
+        std::process::exit(if valid { 0 } else { 1 });
+    }
+
     // Initialize the application state
-    let (listener, amqp_tx) = state::init().await;
+    let (listeners, amqp_tx) = state::init(cli).await;
     log::debug!("Configuration loaded. Listening for incoming connections");
 
-    loop {
-        // Accept all and any incoming connections
-        let (socket, addr) = listener.accept().await?;
-        log::trace!("Incoming connection from: {}", addr);
-
-        // Clone the AMQP sender reference
-        let amqp_tx = amqp_tx.clone();
+    // Every listener accepts connections on its own task
+    let mut servers = tokio::task::JoinSet::new();
+    for (listener, policy_rx) in listeners {
+        servers.spawn(state::serve(listener, policy_rx, amqp_tx.clone()));
+    }
 
-        // Spawn a new task to handle the client connection
-        tokio::spawn(async move {
-            state::handle_connection(socket, addr, amqp_tx).await;
-        });
+    // The daemon stops as soon as any listener fails
+    match servers.join_next().await {
+        Some(result) => result.expect("Listener task panicked"),
+        None => Ok(()),
     }
 }
@@ -0,0 +1,454 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+
+// ------- Constants ------- //
+
+
+// Limits from RFC 5321 section 4.5.3.1
+const MAX_LOCAL_PART_BYTES: usize = 64;
+const MAX_DOMAIN_BYTES: usize = 255;
+
+
+// ------- Enums ------- //
+
+
+/// A reverse-path or forward-path (RFC 5321 section 4.1.2)
+pub enum Path {
+    Null,               // <> used by bounces and other notifications
+    Postmaster,         // <Postmaster> without a domain, only valid as a forward-path
+    Mailbox(Mailbox),   // <local-part@domain>, any source route is dropped
+}
+
+
+// ------- Structs ------- //
+
+
+/// A validated mailbox (local-part@domain) as used in MAIL and RCPT commands.
+/// The domain is kept in both its U-label (Unicode) and A-label (Punycode) forms.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mailbox {
+    local_part: String,
+    domain: String,
+    ascii_domain: String,
+    ascii_input: bool,
+}
+
+
+/// A single ESMTP parameter of a MAIL or RCPT command, with its keyword uppercased
+pub struct Parameter<'a> {
+    pub keyword: String,
+    pub value: Option<&'a str>,
+}
+
+
+// ------- Implementations ------- //
+
+
+impl Mailbox {
+    /// Parse and validate a mailbox, accepting UTF-8 local parts and IDN domains (RFC 6531)
+    pub fn parse(address: &str) -> Result<Mailbox, &'static str> {
+        let (local_part, domain) = split_address(address)?;
+
+        if local_part.len() > MAX_LOCAL_PART_BYTES {
+            return Err("Local part is too long");
+        }
+        validate_local_part(local_part)?;
+
+        let (domain, ascii_domain) = normalise_domain(domain)?;
+
+        Ok(Mailbox {
+            local_part: local_part.to_string(),
+            domain,
+            ascii_domain,
+            ascii_input: address.is_ascii(),
+        })
+    }
+
+    /// Returns true if the mailbox was written in plain ASCII, so it is valid without SMTPUTF8
+    pub fn is_ascii(&self) -> bool {
+        self.ascii_input
+    }
+
+    /// The mailbox with its domain as U-labels
+    pub fn to_unicode(&self) -> String {
+        format!("{}@{}", self.local_part, self.domain)
+    }
+
+    /// The mailbox with its domain as A-labels, only fully ASCII when `is_ascii` is true
+    pub fn to_ascii(&self) -> String {
+        format!("{}@{}", self.local_part, self.ascii_domain)
+    }
+}
+
+
+// ------- Parsers ------- //
+
+
+/// Parse the path at the start of a MAIL or RCPT argument, returning it with the text that follows.
+/// The closing `>` is searched outside of quoted strings, so quoted local parts may contain spaces.
+pub fn parse_path(arg: &str) -> Result<(Path, &str), &'static str> {
+    let Some(inner) = arg.strip_prefix('<') else {
+        // Some clients leave out the angle brackets, the mailbox then runs up to the first space
+        let end = arg.find(' ').unwrap_or(arg.len());
+        if end == 0 {
+            return Err("Missing path");
+        }
+        return Ok((Path::Mailbox(Mailbox::parse(&arg[..end])?), &arg[end..]));
+    };
+
+    let mut quoted = false;
+    let mut escaped = false;
+    let closing = inner
+        .char_indices()
+        .find(|&(_, c)| {
+            let found = !quoted && c == '>';
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if quoted => escaped = true,
+                '"' => quoted = !quoted,
+                _ => {}
+            }
+            found
+        })
+        .map(|(i, _)| i)
+        .ok_or("Unterminated path, missing >")?;
+
+    let (path, rest) = (&inner[..closing], &inner[closing + 1..]);
+    if !rest.is_empty() && !rest.starts_with(' ') {
+        return Err("Expected a space after the path");
+    }
+
+    if path.is_empty() {
+        return Ok((Path::Null, rest));
+    }
+    if path.eq_ignore_ascii_case("Postmaster") {
+        return Ok((Path::Postmaster, rest));
+    }
+
+    let mailbox = if path.starts_with('@') { strip_source_route(path)? } else { path };
+
+    Ok((Path::Mailbox(Mailbox::parse(mailbox)?), rest))
+}
+
+
+/// Parse the ESMTP parameters that follow a path (RFC 5321 section 4.1.2).
+/// Values are returned as sent, decoding xtext is left to the parameters that use it.
+pub fn parse_parameters(params: &str) -> Result<Vec<Parameter<'_>>, &'static str> {
+    let mut parameters: Vec<Parameter> = Vec::new();
+
+    for param in params.split_whitespace() {
+        let (keyword, value) = match param.split_once('=') {
+            Some((keyword, value)) => (keyword, Some(value)),
+            None => (param, None),
+        };
+
+        // esmtp-keyword = (ALPHA / DIGIT) *(ALPHA / DIGIT / "-")
+        let valid_keyword = keyword.bytes().next().is_some_and(|b| b.is_ascii_alphanumeric())
+            && keyword.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-');
+        if !valid_keyword {
+            return Err("Invalid parameter keyword");
+        }
+
+        // esmtp-value = 1*(%d33-60 / %d62-126), extended with UTF-8 by RFC 6531
+        if let Some(value) = value
+            && (value.is_empty() || value.chars().any(|c| c.is_ascii_control() || c == '='))
+        {
+            return Err("Invalid parameter value");
+        }
+
+        let keyword = keyword.to_ascii_uppercase();
+        if parameters.iter().any(|p| p.keyword == keyword) {
+            return Err("Duplicate parameter");
+        }
+        parameters.push(Parameter { keyword, value });
+    }
+
+    Ok(parameters)
+}
+
+
+// ------- Helpers ------- //
+
+
+/// Drop the source route of a path such as `@relay.example,@other.example:user@example.com`.
+/// Source routes are obsolete and must be ignored, but their domains still have to be valid (RFC 5321 section 4.1.2).
+fn strip_source_route(path: &str) -> Result<&str, &'static str> {
+    // The route ends at the first colon outside an address literal
+    let mut literal = false;
+    let colon = path
+        .char_indices()
+        .find(|&(_, c)| {
+            match c {
+                '[' => literal = true,
+                ']' => literal = false,
+                _ => {}
+            }
+            !literal && c == ':'
+        })
+        .map(|(i, _)| i)
+        .ok_or("Invalid source route")?;
+
+    for at_domain in path[..colon].split(',') {
+        let domain = at_domain.strip_prefix('@').ok_or("Invalid source route")?;
+        normalise_domain(domain).map_err(|_| "Invalid domain in source route")?;
+    }
+
+    Ok(&path[colon + 1..])
+}
+
+
+/// Split an address at the `@` that separates the local part from the domain.
+/// A quoted local part may itself contain `@`, so it is scanned up to its closing quote.
+fn split_address(address: &str) -> Result<(&str, &str), &'static str> {
+    let at = if address.starts_with('"') {
+        let mut escaped = false;
+        let closing = address
+            .char_indices()
+            .skip(1)
+            .find(|&(_, c)| {
+                let found = !escaped && c == '"';
+                escaped = !escaped && c == '\\';
+                found
+            })
+            .map(|(i, _)| i)
+            .ok_or("Unterminated quoted local part")?;
+        if address.as_bytes().get(closing + 1) != Some(&b'@') {
+            return Err("Expected @ after quoted local part");
+        }
+        closing + 1
+    } else {
+        address.rfind('@').ok_or("Address is missing an @")?
+    };
+
+    let (local_part, domain) = (&address[..at], &address[at + 1..]);
+    if local_part.is_empty() {
+        return Err("Local part is empty");
+    }
+    if domain.is_empty() {
+        return Err("Domain is empty");
+    }
+
+    Ok((local_part, domain))
+}
+
+
+/// Characters allowed in an atom, extended with any non-ASCII UTF-8 character by RFC 6531
+fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c) || !c.is_ascii()
+}
+
+
+/// Validate a local part as either a dot-atom or a quoted string (RFC 5321 section 4.1.2)
+fn validate_local_part(local_part: &str) -> Result<(), &'static str> {
+    if let Some(quoted) = local_part.strip_prefix('"').and_then(|l| l.strip_suffix('"')) {
+        let mut chars = quoted.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                // quoted-pairSMTP is a backslash followed by any printable ASCII character
+                '\\' => match chars.next() {
+                    Some(' '..='~') => {}
+                    _ => return Err("Invalid escape in quoted local part"),
+                },
+                '"' => return Err("Unescaped quote in quoted local part"),
+                ' '..='~' => {}
+                c if !c.is_ascii() => {}
+                _ => return Err("Invalid character in quoted local part"),
+            }
+        }
+        return Ok(());
+    }
+
+    let valid = local_part
+        .split('.')
+        .all(|atom| !atom.is_empty() && atom.chars().all(is_atext));
+    if !valid {
+        return Err("Invalid character in local part");
+    }
+
+    Ok(())
+}
+
+
+/// Validate a domain and return it in its U-label and A-label forms.
+/// Address literals such as `[192.0.2.1]` or `[IPv6:2001:db8::1]` are returned unchanged.
+fn normalise_domain(domain: &str) -> Result<(String, String), &'static str> {
+    if let Some(literal) = domain.strip_prefix('[').and_then(|d| d.strip_suffix(']')) {
+        let valid = match literal.get(..5) {
+            Some(tag) if tag.eq_ignore_ascii_case("IPv6:") => literal[5..].parse::<Ipv6Addr>().is_ok(),
+            _ => literal.parse::<Ipv4Addr>().is_ok(),
+        };
+        if !valid {
+            return Err("Invalid address literal");
+        }
+        return Ok((domain.to_string(), domain.to_string()));
+    }
+
+    if domain.len() > MAX_DOMAIN_BYTES {
+        return Err("Domain is too long");
+    }
+
+    // UTS 46 processing lowercases the domain and converts between the two label forms
+    let ascii_domain = idna::domain_to_ascii_strict(domain).map_err(|_| "Invalid domain")?;
+    let (unicode_domain, result) = idna::domain_to_unicode(&ascii_domain);
+    result.map_err(|_| "Invalid domain")?;
+
+    Ok((unicode_domain, ascii_domain))
+}
+
+
+/// The A-label form of a domain, used to compare domains however they were written
+pub fn domain_to_ascii(domain: &str) -> Result<String, &'static str> {
+    normalise_domain(domain).map(|(_, ascii_domain)| ascii_domain)
+}
+
+
+/// Decode an xtext encoded ESMTP parameter value, where `+XX` stands for the byte 0xXX (RFC 3461 section 4)
+pub fn decode_xtext(value: &str) -> Result<String, &'static str> {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+
+    while let Some(b) = bytes.next() {
+        match b {
+            b'+' => {
+                let hi = bytes.next().and_then(hex_value);
+                let lo = bytes.next().and_then(hex_value);
+                match (hi, lo) {
+                    (Some(hi), Some(lo)) => decoded.push(hi << 4 | lo),
+                    _ => return Err("Invalid xtext encoding"),
+                }
+            }
+            b'!'..=b'~' if b != b'=' => decoded.push(b),
+            _ => return Err("Invalid character in xtext"),
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|_| "Invalid xtext encoding")
+}
+
+
+/// Value of a single hexadecimal digit
+fn hex_value(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|d| d as u8)
+}
+
+
+// ------- Tests ------- //
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mailbox(arg: &str) -> Mailbox {
+        match parse_path(arg) {
+            Ok((Path::Mailbox(mailbox), _)) => mailbox,
+            _ => panic!("expected a mailbox for {}", arg),
+        }
+    }
+
+    #[test]
+    fn null_path() {
+        assert!(matches!(parse_path("<>"), Ok((Path::Null, ""))));
+        assert!(matches!(parse_path("<> SIZE=10"), Ok((Path::Null, " SIZE=10"))));
+    }
+
+    #[test]
+    fn postmaster() {
+        assert!(matches!(parse_path("<Postmaster>"), Ok((Path::Postmaster, ""))));
+        assert!(matches!(parse_path("<POSTMASTER>"), Ok((Path::Postmaster, ""))));
+        assert_eq!(mailbox("<Postmaster@example.com>").to_ascii(), "Postmaster@example.com");
+    }
+
+    #[test]
+    fn source_route_is_dropped() {
+        assert_eq!(mailbox("<@relay.example,@other.example:user@example.com>").to_ascii(), "user@example.com");
+        assert_eq!(mailbox("<@[192.0.2.1]:user@example.com>").to_ascii(), "user@example.com");
+        assert!(parse_path("<@relay..example:user@example.com>").is_err());
+        assert!(parse_path("<relay.example:user@example.com>").is_err());
+        assert!(parse_path("<@relay.example user@example.com>").is_err());
+    }
+
+    #[test]
+    fn quoted_local_part() {
+        let (path, rest) = parse_path(r#"<"john doe@home"@example.com> SIZE=10"#).unwrap();
+        let Path::Mailbox(quoted) = path else { panic!("expected a mailbox") };
+        assert_eq!(quoted.to_ascii(), r#""john doe@home"@example.com"#);
+        assert_eq!(rest, " SIZE=10");
+
+        assert_eq!(mailbox(r#"<"a\"b>c"@example.com>"#).to_ascii(), r#""a\"b>c"@example.com"#);
+        assert!(parse_path(r#"<"unterminated@example.com>"#).is_err());
+        assert!(parse_path(r#"<"a"b@example.com>"#).is_err());
+    }
+
+    #[test]
+    fn idn_domains() {
+        let unicode = Mailbox::parse("user@Bücher.de").unwrap();
+        assert!(!unicode.is_ascii());
+        assert_eq!(unicode.to_ascii(), "user@xn--bcher-kva.de");
+        assert_eq!(unicode.to_unicode(), "user@bücher.de");
+
+        let ascii = Mailbox::parse("user@xn--bcher-kva.de").unwrap();
+        assert!(ascii.is_ascii());
+        assert_eq!(ascii.to_unicode(), "user@bücher.de");
+        assert_eq!(ascii.to_ascii(), unicode.to_ascii());
+
+        assert_eq!(domain_to_ascii("BÜCHER.de"), Ok("xn--bcher-kva.de".to_string()));
+        assert!(Mailbox::parse("user@xn--zz.de").is_err());
+    }
+
+    #[test]
+    fn address_literals() {
+        assert!(Mailbox::parse("user@[192.0.2.1]").is_ok());
+        assert!(Mailbox::parse("user@[IPv6:2001:db8::1]").is_ok());
+        assert!(Mailbox::parse("user@[300.1.1.1]").is_err());
+        assert!(Mailbox::parse("user@[2001:db8::1]").is_err());
+    }
+
+    #[test]
+    fn invalid_mailboxes() {
+        assert!(Mailbox::parse("user").is_err());
+        assert!(Mailbox::parse("@example.com").is_err());
+        assert!(Mailbox::parse("user@").is_err());
+        assert!(Mailbox::parse("us..er@example.com").is_err());
+        assert!(Mailbox::parse("us(er@example.com").is_err());
+        assert!(Mailbox::parse(&format!("{}@example.com", "a".repeat(MAX_LOCAL_PART_BYTES + 1))).is_err());
+        assert!(Mailbox::parse("用户@例子.广告").is_ok());
+    }
+
+    #[test]
+    fn unbracketed_path() {
+        let (path, rest) = parse_path("user@example.com SIZE=10").unwrap();
+        assert!(matches!(path, Path::Mailbox(mailbox) if mailbox.to_ascii() == "user@example.com"));
+        assert_eq!(rest, " SIZE=10");
+
+        assert!(parse_path("").is_err());
+        assert!(parse_path(" user@example.com").is_err());
+        assert!(parse_path("<user@example.com").is_err());
+        assert!(parse_path("<user@example.com>SIZE=10").is_err());
+    }
+
+    #[test]
+    fn parameters() {
+        let parameters = parse_parameters(" size=10 SMTPUTF8 body=8BITMIME").unwrap();
+        let parsed = parameters.iter().map(|p| (p.keyword.as_str(), p.value)).collect::<Vec<_>>();
+        assert_eq!(parsed, [("SIZE", Some("10")), ("SMTPUTF8", None), ("BODY", Some("8BITMIME"))]);
+
+        assert!(parse_parameters("").unwrap().is_empty());
+        assert_eq!(parse_parameters("SIZE=1 size=2").err(), Some("Duplicate parameter"));
+        assert_eq!(parse_parameters("-SIZE=1").err(), Some("Invalid parameter keyword"));
+        assert_eq!(parse_parameters("SIZE=").err(), Some("Invalid parameter value"));
+        assert_eq!(parse_parameters("ENVID=a=b").err(), Some("Invalid parameter value"));
+    }
+
+    #[test]
+    fn xtext() {
+        assert_eq!(decode_xtext("user+40example.com"), Ok("user@example.com".to_string()));
+        assert_eq!(decode_xtext("a+2Bb+2b"), Ok("a+b+".to_string()));
+        assert_eq!(decode_xtext("+C3+BC"), Ok("ü".to_string()));
+        assert!(decode_xtext("a+2").is_err());
+        assert!(decode_xtext("a+ZZ").is_err());
+        assert!(decode_xtext("a b").is_err());
+        assert!(decode_xtext("a=b").is_err());
+        assert!(decode_xtext("+FF").is_err());
+    }
+}
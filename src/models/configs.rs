@@ -1,5 +1,54 @@
+use crate::errors::ConfigError;
+use super::address::domain_to_ascii;
 use std::env::var as env_var;
-use std::sync::LazyLock;
+use std::fmt::Display;
+use std::str::FromStr;
+use serde::Deserialize;
+use log::LevelFilter;
+
+
+// ------- Constants ------- //
+
+
+// Configuration file read when CONFIG_FILE is not set, if it exists
+const DEFAULT_CONFIG_FILE: &str = "/etc/lsmtpd/lsmtpd.toml";
+
+// Text of the greeting banner after the server name, unless a listener sets its own
+const DEFAULT_BANNER: &str = "LSMTP Server (Rust)";
+
+// Maximum length of an SMTP command line including the CRLF (RFC 5321 section 4.5.3.1.4)
+const DEFAULT_MAX_COMMAND_LINE_BYTES: usize = 512;
+
+// Maximum length of a DATA text line including the CRLF (RFC 5321 section 4.5.3.1.6)
+const DEFAULT_MAX_TEXT_LINE_BYTES: usize = 1000;
+
+// Number of overlong lines a client may send before the connection is dropped
+const DEFAULT_MAX_LINE_VIOLATIONS: usize = 3;
+
+// Time to wait for the broker to confirm an email before replying to DATA, when waiting for confirms
+const DEFAULT_CONFIRM_TIMEOUT_SECS: u64 = 30;
+
+
+// ------- Enums ------- //
+
+
+/// The behaviour of a listener
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    Mx,             // Inbound mail from other servers (RFC 5321), usually port 25
+    Submission,     // Mail from authenticated users (RFC 6409), usually port 587
+}
+
+
+/// How a listener offers TLS
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerTls {
+    None,           // Plaintext only
+    StartTls,       // Plaintext with an optional STARTTLS upgrade
+    Implicit,       // TLS from the first byte (SMTPS), usually port 465
+}
 
 
 // ------- Structs ------- //
@@ -14,108 +63,520 @@ pub struct AMQPConfig {
     pub exchange: String,
     pub routing_key: String,
     pub buffer_size: usize,
+    pub wait_for_confirm: bool,
+    pub confirm_timeout_secs: u64,
 }
 
 
-pub struct BaseConfig {
+/// Offline validation of OAuth2 access tokens for the XOAUTH2 and OAUTHBEARER mechanisms
+pub struct OAuthConfig {
+    pub jwks_file: String,
+    pub issuer: String,
+    pub audience: String,
+}
+
+
+/// Line limits and timings applied to every session, whichever listener accepted it
+pub struct SessionLimits {
+    pub max_command_line_bytes: usize,
+    pub max_text_line_bytes: usize,
+    pub max_line_violations: usize,
+    pub greeting_delay_ms: u64,
+}
+
+
+/// A single address the daemon accepts connections on, with the limits and routing applied to its sessions
+#[derive(Clone)]
+pub struct ListenerConfig {
+    pub name: String,
     bind_address: String,
     bind_port: u16,
+    pub profile: Profile,
+    pub tls: ListenerTls,
+    pub max_email_size: usize,
+    pub max_timeout_secs: u64,
+    pub banner: String,
+    pub routing_key: Option<String>,
+}
+
+
+/// The validated configuration of the daemon, shared by the listeners, handlers and the publisher
+pub struct BaseConfig {
+    pub server_name: String,
+    pub log_level: Option<LevelFilter>,
+    pub temp_email_dir: String,
+    pub durable_spool: bool,
+    pub limits: SessionLimits,
+    pub listeners: Vec<ListenerConfig>,
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    pub auth_users_file: Option<String>,
+    pub oauth: Option<OAuthConfig>,
+    pub sender_ownership_file: Option<String>,
+    pub enforce_header_from: bool,
+    pub accepted_domains: Option<Vec<String>>,
     pub amqp_details: AMQPConfig,
 }
 
 
-// ------- Static Variables ------- //
+/// The configuration file as written, before environment overrides and validation.
+/// Every key can be overridden by the environment variable named after its section and key
+/// (e.g. `[amqp] host` by AMQP_HOST), and unknown keys are rejected so that a typo is reported.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    server_name: Option<String>,
+    log_level: Option<String>,
+    temp_email_dir: Option<String>,
+    durable_spool: Option<bool>,
+    bind_address: Option<String>,
+    max_email_size_bytes: Option<usize>,
+    max_timeout_secs: Option<u64>,
+    max_command_line_bytes: Option<usize>,
+    max_text_line_bytes: Option<usize>,
+    max_line_violations: Option<usize>,
+    greeting_delay_ms: Option<u64>,
+    accepted_domains: Option<Vec<String>>,
+    sender_ownership_file: Option<String>,
+    enforce_header_from: Option<bool>,
+    tls: TlsSection,
+    auth: AuthSection,
+    oauth: OAuthSection,
+    amqp: AMQPSection,
+    listeners: Vec<ListenerSection>,
+}
+
 
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct TlsSection {
+    cert_path: Option<String>,
+    key_path: Option<String>,
+}
 
-// Name of the server, used only at greeting, HELO, EHLO commands
-pub static SERVER_NAME: LazyLock<String> = LazyLock::new(|| {
-    // Read the SERVER_NAME environment variable and panic if it's not set
-    env_var("SERVER_NAME").expect("SERVER_NAME must be set")
-});
 
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct AuthSection {
+    users_file: Option<String>,
+}
 
-// Maximum email size that the server will accept
-pub static MAX_EMAIL_SIZE_BYTES: LazyLock<usize> = LazyLock::new(|| {
-    // Read the MAX_EMAIL_SIZE_BYTES environment variable
-    // and panic if it's not set or cannot be parsed as a usize
-    env_var("MAX_EMAIL_SIZE_BYTES")
-        .expect("MAX_EMAIL_SIZE_BYTES must be set to a valid usize")
-        .parse::<usize>()
-        .expect("MAX_EMAIL_SIZE_BYTES must be set to a valid usize")
-});
 
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct OAuthSection {
+    jwks_file: Option<String>,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
 
-// Temporary email storage directory if the AMQP publish fails
-pub static TEMP_EMAIL_DIR: LazyLock<String> = LazyLock::new(|| {
-    // Read the TEMP_EMAIL_DIR environment variable and panic if it's not set
-    env_var("TEMP_EMAIL_DIR").expect("TEMP_EMAIL_DIR must be set to a valid directory path")
-});
 
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct AMQPSection {
+    host: Option<String>,
+    port: Option<u16>,
+    username: Option<String>,
+    password: Option<String>,
+    vhost: Option<String>,
+    exchange: Option<String>,
+    routing_key: Option<String>,
+    buffer_size: Option<usize>,
+    wait_for_confirm: Option<bool>,
+    confirm_timeout_secs: Option<u64>,
+}
 
-// Maximum timeout for client connections in seconds
-pub static MAX_TIMEOUT_SECS: LazyLock<u64> = LazyLock::new(|| {
-    // Read the MAX_TIMEOUT_SECS environment variable
-    // and panic if it's not set or cannot be parsed as a u64
-    env_var("MAX_TIMEOUT_SECS")
-        .expect("MAX_TIMEOUT_SECS must be set to a valid u64")
-        .parse::<u64>()
-        .expect("MAX_TIMEOUT_SECS must be set to a valid u64")
-});
+
+/// A `[[listeners]]` table, overridden by the LISTENER_<NAME>_* environment variables
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ListenerSection {
+    name: String,
+    bind_address: Option<String>,
+    bind_port: Option<u16>,
+    profile: Option<Profile>,
+    tls: Option<ListenerTls>,
+    max_email_size_bytes: Option<usize>,
+    max_timeout_secs: Option<u64>,
+    banner: Option<String>,
+    routing_key: Option<String>,
+}
+
+
+/// Global settings a listener falls back to when it does not set its own
+struct ListenerDefaults {
+    bind_address: Option<String>,
+    max_email_size: Option<usize>,
+    max_timeout_secs: Option<u64>,
+    tls: ListenerTls,
+}
 
 
 // ------- Implementations ------- //
 
 
+impl FromStr for Profile {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "mx" => Ok(Profile::Mx),
+            "submission" => Ok(Profile::Submission),
+            _ => Err("expected mx or submission"),
+        }
+    }
+}
+
+
+impl FromStr for ListenerTls {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "none" => Ok(ListenerTls::None),
+            "starttls" => Ok(ListenerTls::StartTls),
+            "implicit" => Ok(ListenerTls::Implicit),
+            _ => Err("expected none, starttls or implicit"),
+        }
+    }
+}
+
+
 impl BaseConfig {
-    /// Reads configuration from environment variables and returns a BaseConfig instance.
-    pub fn from_env() -> Self {
-        let bind_address = env_var("BIND_ADDRESS")
-            .expect("BIND_ADDRESS must be set to a valid IP address or hostname");
-        let bind_port = env_var("BIND_PORT")
-            .expect("BIND_PORT must be set to a valid u16")
-            .parse::<u16>()
-            .expect("BIND_PORT must be set to a valid u16");
-
-        let amqp_host = env_var("AMQP_HOST")
-            .expect("AMQP_HOST must be set");
-        let amqp_port = env_var("AMQP_PORT")
-            .expect("AMQP_PORT must be set to a valid u16")
-            .parse::<u16>()
-            .expect("AMQP_PORT must be set to a valid u16");
-        let amqp_username = env_var("AMQP_USERNAME")
-            .expect("AMQP_USERNAME must be set");
-        let amqp_password = env_var("AMQP_PASSWORD")
-            .expect("AMQP_PASSWORD must be set");
-        let amqp_vhost = env_var("AMQP_VHOST")
-            .expect("AMQP_VHOST must be set");
-        let amqp_exchange = env_var("AMQP_EXCHANGE")
-            .expect("AMQP_EXCHANGE must be set");
-        let amqp_routing_key = env_var("AMQP_ROUTING_KEY")
-            .expect("AMQP_ROUTING_KEY must be set");
-        let amqp_buffer_size = env_var("AMQP_BUFFER_SIZE")
-            .expect("AMQP_BUFFER_SIZE must be set to a valid usize")
-            .parse::<usize>()
-            .expect("AMQP_BUFFER_SIZE must be set to a valid usize");
-
-        log::info!("All environment variables have been loaded");
+    /// Reads the configuration file, applies the environment variable overrides and validates the result.
+    /// The file is the given path, CONFIG_FILE, or /etc/lsmtpd/lsmtpd.toml if it exists. Without one, only the environment is used.
+    pub fn load(path: Option<&str>) -> Result<Self, ConfigError> {
+        let path = path
+            .map(str::to_string)
+            .or_else(|| env_var("CONFIG_FILE").ok())
+            .or_else(|| std::path::Path::new(DEFAULT_CONFIG_FILE).exists().then(|| DEFAULT_CONFIG_FILE.to_string()));
+
+        let mut file = match &path {
+            Some(path) => ConfigFile::read(path)?,
+            None => ConfigFile::default(),
+        };
+
+        let mut problems = Vec::new();
+        file.apply_env(&mut problems);
+        let config = file.validate(&mut problems);
+        if !problems.is_empty() {
+            return Err(ConfigError::Invalid(problems));
+        }
+
+        log::info!("Configuration loaded from {} and the environment", path.as_deref().unwrap_or("defaults"));
+        Ok(config)
+    }
+}
+
+
+impl ConfigFile {
+    /// Reads and deserialises a TOML configuration file
+    fn read(path: &str) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::Read(path.to_string(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_string(), e))
+    }
+
+    /// Replaces settings with the environment variables that are set
+    fn apply_env(&mut self, problems: &mut Vec<String>) {
+        env_override(&mut self.server_name, "SERVER_NAME", problems);
+        env_override(&mut self.log_level, "LOG_LEVEL", problems);
+        env_override(&mut self.temp_email_dir, "TEMP_EMAIL_DIR", problems);
+        env_override(&mut self.durable_spool, "DURABLE_SPOOL", problems);
+        env_override(&mut self.bind_address, "BIND_ADDRESS", problems);
+        env_override(&mut self.max_email_size_bytes, "MAX_EMAIL_SIZE_BYTES", problems);
+        env_override(&mut self.max_timeout_secs, "MAX_TIMEOUT_SECS", problems);
+        env_override(&mut self.max_command_line_bytes, "MAX_COMMAND_LINE_BYTES", problems);
+        env_override(&mut self.max_text_line_bytes, "MAX_TEXT_LINE_BYTES", problems);
+        env_override(&mut self.max_line_violations, "MAX_LINE_VIOLATIONS", problems);
+        env_override(&mut self.greeting_delay_ms, "GREETING_DELAY_MS", problems);
+        env_override(&mut self.sender_ownership_file, "SENDER_OWNERSHIP_FILE", problems);
+        env_override(&mut self.enforce_header_from, "ENFORCE_HEADER_FROM", problems);
+        if let Ok(domains) = env_var("ACCEPTED_DOMAINS") {
+            self.accepted_domains = Some(domains.split(',').map(str::to_string).collect());
+        }
+
+        env_override(&mut self.tls.cert_path, "TLS_CERT_PATH", problems);
+        env_override(&mut self.tls.key_path, "TLS_KEY_PATH", problems);
+        env_override(&mut self.auth.users_file, "AUTH_USERS_FILE", problems);
+        env_override(&mut self.oauth.jwks_file, "OAUTH_JWKS_FILE", problems);
+        env_override(&mut self.oauth.issuer, "OAUTH_ISSUER", problems);
+        env_override(&mut self.oauth.audience, "OAUTH_AUDIENCE", problems);
+
+        env_override(&mut self.amqp.host, "AMQP_HOST", problems);
+        env_override(&mut self.amqp.port, "AMQP_PORT", problems);
+        env_override(&mut self.amqp.username, "AMQP_USERNAME", problems);
+        env_override(&mut self.amqp.password, "AMQP_PASSWORD", problems);
+        env_override(&mut self.amqp.vhost, "AMQP_VHOST", problems);
+        env_override(&mut self.amqp.exchange, "AMQP_EXCHANGE", problems);
+        env_override(&mut self.amqp.routing_key, "AMQP_ROUTING_KEY", problems);
+        env_override(&mut self.amqp.buffer_size, "AMQP_BUFFER_SIZE", problems);
+        env_override(&mut self.amqp.wait_for_confirm, "AMQP_WAIT_FOR_CONFIRM", problems);
+        env_override(&mut self.amqp.confirm_timeout_secs, "AMQP_CONFIRM_TIMEOUT_SECS", problems);
+
+        // LISTENERS picks the listeners by name, keeping the file settings of those it defines
+        if let Ok(names) = env_var("LISTENERS") {
+            let mut defined = std::mem::take(&mut self.listeners);
+            self.listeners = names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| match defined.iter().position(|listener| listener.name == name) {
+                    Some(i) => defined.swap_remove(i),
+                    None => ListenerSection { name: name.to_string(), ..Default::default() },
+                })
+                .collect();
+        }
+        if self.listeners.is_empty() {
+            self.legacy_listeners_from_env(problems);
+        }
+
+        for listener in &mut self.listeners {
+            listener.apply_env(problems);
+        }
+    }
+
+    /// Reads the listeners of a configuration that predates listener tables from
+    /// BIND_PORT and IMPLICIT_TLS, plus one for SUBMISSION_BIND_PORT
+    fn legacy_listeners_from_env(&mut self, problems: &mut Vec<String>) {
+        let mut default = ListenerSection { name: "default".to_string(), ..Default::default() };
+        env_override(&mut default.bind_port, "BIND_PORT", problems);
+
+        // Implicit TLS (SMTPS) wraps every connection in TLS before the greeting
+        let mut implicit_tls = None;
+        env_override(&mut implicit_tls, "IMPLICIT_TLS", problems);
+        if implicit_tls == Some(true) {
+            default.tls = Some(ListenerTls::Implicit);
+        }
+        if default.bind_port.is_some() {
+            self.listeners.push(default);
+        }
+
+        // Submission always starts in plaintext and is upgraded with STARTTLS before AUTH (RFC 6409 section 4.3)
+        let mut submission = ListenerSection {
+            name: "submission".to_string(),
+            profile: Some(Profile::Submission),
+            ..Default::default()
+        };
+        env_override(&mut submission.bind_port, "SUBMISSION_BIND_PORT", problems);
+        if submission.bind_port.is_some() {
+            self.listeners.push(submission);
+        }
+    }
+
+    /// Checks every setting and builds the configuration, recording each problem found.
+    /// The returned configuration is only meaningful when no problems were recorded.
+    fn validate(self, problems: &mut Vec<String>) -> BaseConfig {
+        let server_name = required(self.server_name, "server_name (SERVER_NAME)", problems);
+        let temp_email_dir = required(self.temp_email_dir, "temp_email_dir (TEMP_EMAIL_DIR)", problems);
+        check_writable_dir(&temp_email_dir, "temp_email_dir (TEMP_EMAIL_DIR)", problems);
+
+        // The level of the daemon's own messages, RUST_LOG decides when unset
+        let log_level = self.log_level.and_then(|level| match level.parse::<LevelFilter>() {
+            Ok(level) => Some(level),
+            Err(_) => {
+                problems.push(format!("log_level (LOG_LEVEL) must be off, error, warn, info, debug or trace, got {:?}", level));
+                None
+            }
+        });
+
+        let limits = SessionLimits {
+            max_command_line_bytes: self.max_command_line_bytes.unwrap_or(DEFAULT_MAX_COMMAND_LINE_BYTES),
+            max_text_line_bytes: self.max_text_line_bytes.unwrap_or(DEFAULT_MAX_TEXT_LINE_BYTES),
+            max_line_violations: self.max_line_violations.unwrap_or(DEFAULT_MAX_LINE_VIOLATIONS),
+            greeting_delay_ms: self.greeting_delay_ms.unwrap_or(0),
+        };
+        if limits.max_command_line_bytes < DEFAULT_MAX_COMMAND_LINE_BYTES {
+            problems.push(format!("max_command_line_bytes (MAX_COMMAND_LINE_BYTES) must be at least {}", DEFAULT_MAX_COMMAND_LINE_BYTES));
+        }
+        if limits.max_text_line_bytes < DEFAULT_MAX_TEXT_LINE_BYTES {
+            problems.push(format!("max_text_line_bytes (MAX_TEXT_LINE_BYTES) must be at least {}", DEFAULT_MAX_TEXT_LINE_BYTES));
+        }
+        if limits.max_line_violations == 0 {
+            problems.push("max_line_violations (MAX_LINE_VIOLATIONS) must be at least 1".to_string());
+        }
+
+        // TLS is optional, but the certificate and key must be given together
+        let tls_cert_path = self.tls.cert_path;
+        let tls_key_path = self.tls.key_path;
+        if tls_cert_path.is_some() != tls_key_path.is_some() {
+            problems.push("tls.cert_path (TLS_CERT_PATH) and tls.key_path (TLS_KEY_PATH) must be set together".to_string());
+        }
+        check_file(&tls_cert_path, "tls.cert_path (TLS_CERT_PATH)", problems);
+        check_file(&tls_key_path, "tls.key_path (TLS_KEY_PATH)", problems);
+
+        // SMTP AUTH is optional and only offered over TLS, so it needs a certificate to be usable
+        let auth_users_file = self.auth.users_file;
+        if auth_users_file.is_some() && tls_cert_path.is_none() {
+            problems.push("auth.users_file (AUTH_USERS_FILE) requires tls.cert_path and tls.key_path to be set".to_string());
+        }
+        check_file(&auth_users_file, "auth.users_file (AUTH_USERS_FILE)", problems);
+
+        // OAuth2 tokens are validated against a local JWKS, the issuer and audience must be given with it
+        let oauth = match (self.oauth.jwks_file, self.oauth.issuer, self.oauth.audience) {
+            (Some(jwks_file), Some(issuer), Some(audience)) => Some(OAuthConfig { jwks_file, issuer, audience }),
+            (None, None, None) => None,
+            _ => {
+                problems.push("oauth.jwks_file, oauth.issuer and oauth.audience (OAUTH_*) must be set together".to_string());
+                None
+            }
+        };
+        if oauth.is_some() && tls_cert_path.is_none() {
+            problems.push("oauth.jwks_file (OAUTH_JWKS_FILE) requires tls.cert_path and tls.key_path to be set".to_string());
+        }
+        check_file(&oauth.as_ref().map(|oauth| oauth.jwks_file.clone()), "oauth.jwks_file (OAUTH_JWKS_FILE)", problems);
+
+        let sender_ownership_file = self.sender_ownership_file;
+        check_file(&sender_ownership_file, "sender_ownership_file (SENDER_OWNERSHIP_FILE)", problems);
+
+        // Domains the MX listeners accept recipients for as A-labels, any domain is accepted when unset
+        let accepted_domains = self.accepted_domains.map(|domains| {
+            domains
+                .iter()
+                .map(|d| d.trim())
+                .filter(|d| !d.is_empty())
+                .filter_map(|d| match domain_to_ascii(d) {
+                    Ok(domain) => Some(domain),
+                    Err(_) => {
+                        problems.push(format!("accepted_domains (ACCEPTED_DOMAINS) contains an invalid domain: {}", d));
+                        None
+                    }
+                })
+                .collect::<Vec<_>>()
+        });
+
         let amqp_details = AMQPConfig {
-            host: amqp_host,
-            port: amqp_port,
-            username: amqp_username,
-            password: amqp_password,
-            vhost: amqp_vhost,
-            exchange: amqp_exchange,
-            routing_key: amqp_routing_key,
-            buffer_size: amqp_buffer_size,
+            host: required(self.amqp.host, "amqp.host (AMQP_HOST)", problems),
+            port: required(self.amqp.port, "amqp.port (AMQP_PORT)", problems),
+            username: required(self.amqp.username, "amqp.username (AMQP_USERNAME)", problems),
+            password: required(self.amqp.password, "amqp.password (AMQP_PASSWORD)", problems),
+            vhost: required(self.amqp.vhost, "amqp.vhost (AMQP_VHOST)", problems),
+            exchange: required(self.amqp.exchange, "amqp.exchange (AMQP_EXCHANGE)", problems),
+            routing_key: required(self.amqp.routing_key, "amqp.routing_key (AMQP_ROUTING_KEY)", problems),
+            buffer_size: required(self.amqp.buffer_size, "amqp.buffer_size (AMQP_BUFFER_SIZE)", problems),
+            // Durable mode only replies 250 to DATA once the email is confirmed by the broker or spooled to disk
+            wait_for_confirm: self.amqp.wait_for_confirm.unwrap_or(false),
+            confirm_timeout_secs: self.amqp.confirm_timeout_secs.unwrap_or(DEFAULT_CONFIRM_TIMEOUT_SECS),
+        };
+        if self.amqp.buffer_size == Some(0) {
+            problems.push("amqp.buffer_size (AMQP_BUFFER_SIZE) must be at least 1".to_string());
+        }
+        if self.amqp.confirm_timeout_secs == Some(0) {
+            problems.push("amqp.confirm_timeout_secs (AMQP_CONFIRM_TIMEOUT_SECS) must be at least 1".to_string());
+        }
+
+        // Every listener is validated against the global defaults and the TLS and AUTH settings it depends on
+        let defaults = ListenerDefaults {
+            bind_address: self.bind_address,
+            max_email_size: self.max_email_size_bytes,
+            max_timeout_secs: self.max_timeout_secs,
+            tls: if tls_cert_path.is_some() { ListenerTls::StartTls } else { ListenerTls::None },
         };
+        let listeners = self.listeners
+            .into_iter()
+            .map(|listener| listener.validate(&defaults, problems))
+            .collect::<Vec<_>>();
+
+        if listeners.is_empty() {
+            problems.push("At least one listener must be configured with [[listeners]], LISTENERS or BIND_PORT".to_string());
+        }
+        for (i, listener) in listeners.iter().enumerate() {
+            if listeners[..i].iter().any(|other| other.name == listener.name) {
+                problems.push(format!("Listener {} is defined more than once", listener.name));
+            } else if listeners[..i].iter().any(|other| other.bind_uri() == listener.bind_uri()) {
+                problems.push(format!("Listener {} binds to {} which is already in use by another listener", listener.name, listener.bind_uri()));
+            }
+            if listener.tls != ListenerTls::None && tls_cert_path.is_none() {
+                problems.push(format!("Listener {} uses TLS, which requires tls.cert_path and tls.key_path to be set", listener.name));
+            }
+            if listener.profile == Profile::Submission && auth_users_file.is_none() && oauth.is_none() {
+                problems.push(format!("Listener {} is a submission listener, which requires auth.users_file or oauth to be set", listener.name));
+            }
+            // AUTH is only offered over TLS and submission requires AUTH, so without TLS every message would be refused
+            if listener.profile == Profile::Submission && listener.tls == ListenerTls::None {
+                problems.push(format!("Listener {} is a submission listener, which requires tls = \"starttls\" or \"implicit\"", listener.name));
+            }
+        }
 
         BaseConfig {
+            server_name,
+            log_level,
+            temp_email_dir,
+            durable_spool: self.durable_spool.unwrap_or(false),
+            limits,
+            listeners,
+            tls_cert_path,
+            tls_key_path,
+            auth_users_file,
+            oauth,
+            sender_ownership_file,
+            enforce_header_from: self.enforce_header_from.unwrap_or(false),
+            accepted_domains,
+            amqp_details,
+        }
+    }
+}
+
+
+impl ListenerSection {
+    /// Replaces settings with the LISTENER_<NAME>_* environment variables that are set
+    fn apply_env(&mut self, problems: &mut Vec<String>) {
+        let prefix = format!("LISTENER_{}_", self.name.to_uppercase().replace('-', "_"));
+
+        env_override(&mut self.bind_address, &format!("{}BIND_ADDRESS", prefix), problems);
+        env_override(&mut self.bind_port, &format!("{}BIND_PORT", prefix), problems);
+        env_override(&mut self.profile, &format!("{}PROFILE", prefix), problems);
+        env_override(&mut self.tls, &format!("{}TLS", prefix), problems);
+        env_override(&mut self.max_email_size_bytes, &format!("{}MAX_EMAIL_SIZE_BYTES", prefix), problems);
+        env_override(&mut self.max_timeout_secs, &format!("{}MAX_TIMEOUT_SECS", prefix), problems);
+        env_override(&mut self.banner, &format!("{}BANNER", prefix), problems);
+        env_override(&mut self.routing_key, &format!("{}ROUTING_KEY", prefix), problems);
+    }
+
+    /// Checks the listener settings and fills in the global defaults, recording each problem found
+    fn validate(self, defaults: &ListenerDefaults, problems: &mut Vec<String>) -> ListenerConfig {
+        if self.name.is_empty() {
+            problems.push("Every listener must have a name".to_string());
+        }
+        let key = |setting: &str| format!("listener {}: {}", self.name, setting);
+
+        let bind_address = required(
+            self.bind_address.or_else(|| defaults.bind_address.clone()),
+            &key("bind_address, or the global bind_address (BIND_ADDRESS)"),
+            problems,
+        );
+        let bind_port = required(self.bind_port, &key("bind_port"), problems);
+        let max_email_size = required(
+            self.max_email_size_bytes.or(defaults.max_email_size),
+            &key("max_email_size_bytes, or the global max_email_size_bytes (MAX_EMAIL_SIZE_BYTES)"),
+            problems,
+        );
+        let max_timeout_secs = self.max_timeout_secs.or(defaults.max_timeout_secs);
+        if max_timeout_secs == Some(0) {
+            problems.push(format!("{} must be at least 1", key("max_timeout_secs")));
+        }
+        let max_timeout_secs = required(
+            max_timeout_secs,
+            &key("max_timeout_secs, or the global max_timeout_secs (MAX_TIMEOUT_SECS)"),
+            problems,
+        );
+
+        ListenerConfig {
             bind_address,
             bind_port,
-            amqp_details,
+            profile: self.profile.unwrap_or(Profile::Mx),
+            tls: self.tls.unwrap_or(defaults.tls),
+            max_email_size,
+            max_timeout_secs,
+            banner: self.banner.unwrap_or_else(|| DEFAULT_BANNER.to_string()),
+            routing_key: self.routing_key,
+            name: self.name,
         }
     }
+}
 
+
+impl ListenerConfig {
     /// Returns the bind URI in the format "address:port".
     pub fn bind_uri(&self) -> String {
         format!("{}:{}", self.bind_address, self.bind_port)
@@ -132,3 +593,53 @@ impl AMQPConfig {
         )
     }
 }
+
+
+// ------- Helpers ------- //
+
+
+/// Replaces a setting with the environment variable, if it is set, recording a problem if it cannot be parsed
+fn env_override<T: FromStr>(setting: &mut Option<T>, name: &str, problems: &mut Vec<String>)
+where
+    T::Err: Display,
+{
+    let Ok(value) = env_var(name) else {
+        return;
+    };
+    match value.parse::<T>() {
+        Ok(parsed) => *setting = Some(parsed),
+        Err(e) => problems.push(format!("{} has an invalid value {:?}: {}", name, value, e)),
+    }
+}
+
+
+/// Returns a required setting, recording a problem if it was not set
+fn required<T: Default>(setting: Option<T>, key: &str, problems: &mut Vec<String>) -> T {
+    setting.unwrap_or_else(|| {
+        problems.push(format!("{} must be set", key));
+        T::default()
+    })
+}
+
+
+/// Records a problem if a directory can not be created or written to, creating it if it does not exist
+fn check_writable_dir(path: &str, key: &str, problems: &mut Vec<String>) {
+    if path.is_empty() {
+        return;
+    }
+    let probe = std::path::Path::new(path).join(".lsmtpd-write-test");
+    let writable = std::fs::create_dir_all(path)
+        .and_then(|_| std::fs::write(&probe, b""))
+        .and_then(|_| std::fs::remove_file(&probe));
+    if let Err(e) = writable {
+        problems.push(format!("{} points to {}, which is not a writable directory: {}", key, path, e));
+    }
+}
+
+
+/// Records a problem if a configured file does not exist
+fn check_file(path: &Option<String>, key: &str, problems: &mut Vec<String>) {
+    if let Some(path) = path && !std::path::Path::new(path).is_file() {
+        problems.push(format!("{} points to {}, which is not a readable file", key, path));
+    }
+}
@@ -1,10 +1,13 @@
-use super::configs::{SERVER_NAME, MAX_EMAIL_SIZE_BYTES};
-use serde::Serialize;
+use super::headers::{has_header, header_addresses, header_value};
+use super::address::{Mailbox, Parameter, Path, decode_xtext, parse_parameters, parse_path};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use serde::{Deserialize, Serialize};
 
 
 // ------- Enums ------- //
 
 
+#[allow(clippy::upper_case_acronyms)]
 pub enum SMTPCommand {
     EHLO,       // Extended HELO
     HELO,       // Hello
@@ -15,6 +18,10 @@ pub enum SMTPCommand {
     Data,       // Email Raw Data
     Dot,        // End of data
 
+    StartTls,   // Upgrade to TLS
+    Bdat,       // Binary data chunk (CHUNKING)
+    Auth,       // SASL authentication
+
     Quit,       // Close connection
     Noop,       // No operation
     Reset,      // Reset all
@@ -22,16 +29,56 @@ pub enum SMTPCommand {
 }
 
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SessionState {
+    Connected,  // Greeting sent, waiting for HELO/EHLO
+    Greeted,    // HELO/EHLO received, no mail transaction in progress
+    Mail,       // MAIL FROM accepted
+    Rcpt,       // At least one RCPT TO accepted
+    Data,       // Receiving the message content
+    Bdat,       // Receiving the message content in BDAT chunks
+}
+
+
 pub enum SMTPResponse {
-    Ok,                 // 250 OK
-    Bye,                // 221 Bye
+    Ok,                 // 250 2.0.0 OK
+    Bye,                // 221 2.0.0 Bye
     Data,               // 354 End data with <CR><LF>.<CR><LF>
-    NotImplemented,     // 502 Command not implemented
-    SizeExceeded,       // 552 Message size exceeds fixed maximum message size
-
-    Greet,              // 220 <server> LSMTP Server (Rust)
+    NotImplemented,     // 502 5.5.1 Command not implemented
+    SizeExceeded,       // 552 5.3.4 Message size exceeds fixed maximum message size
+    BadSequence,        // 503 5.5.1 Bad sequence of commands
+    TransactionFailed,  // 554 5.6.0 Transaction failed
+    NoValidRecipients,  // 554 5.5.1 No valid recipients
+    LocalError,         // 451 4.3.0 Requested action aborted: local error in processing
+    Timeout,            // 421 4.4.2 <server> Timeout exceeded, closing connection
+    SyncError,          // 554 5.5.1 SMTP synchronization error
+    LineTooLong,        // 500 5.5.2 Line too long
+    InvalidUtf8,        // 500 5.5.2 Syntax error, command is not valid UTF-8
+    InvalidSender(&'static str),    // 501 5.1.7 <reason>
+    InvalidRecipient(&'static str), // 501 5.1.3 <reason>
+    UnknownParameter,   // 555 5.5.4 MAIL FROM/RCPT TO parameters not recognized or not implemented
+    InvalidParameter(&'static str), // 501 5.5.4 <reason>
+    Utf8NotPermitted,   // 553 5.6.7 Non-ASCII addresses require SMTPUTF8
+    NotAuthorized(&'static str),    // 550 5.7.1 <reason>
+    InvalidBdat,        // 501 5.5.4 Syntax error in BDAT parameters
+    ChunkReceived(usize),   // 250 2.0.0 <size> octets received
+    TooManyErrors,      // 421 4.7.0 <server> Too many errors, closing connection
+
+    StartTls,           // 220 2.0.0 Ready to start TLS
+    TlsNotAvailable,    // 454 4.7.0 TLS not available due to temporary reason
+
+    AuthChallenge(&'static str),    // 334 <base64 challenge>
+    AuthSuccess,        // 235 2.7.0 Authentication successful
+    AuthFailed,         // 535 5.7.8 Authentication credentials invalid
+    AuthCancelled,      // 501 5.0.0 Authentication cancelled
+    AuthInvalidResponse,    // 501 5.5.2 Cannot Base64-decode client response
+    AuthUnsupported,    // 504 5.5.4 Unrecognized authentication type
+    EncryptionRequired, // 538 5.7.11 Encryption required for requested authentication mechanism
+    AuthRequired,       // 530 5.7.0 Authentication required
+
+    Greet(String),      // 220 <server> <banner>
     Helo,               // 250 <server>
-    Ehlo,               // 250-<server>  250-SIZE <max_size>  250-8BITMIME  250 OK
+    Ehlo { starttls: bool, auth: Option<String>, max_size: usize },   // 250-<server>  250-SIZE <max_size>  250-8BITMIME  250-PIPELINING  250-ENHANCEDSTATUSCODES  250-SMTPUTF8  250-CHUNKING  250-BINARYMIME  250-DSN  [250-STARTTLS]  [250-AUTH <mechanisms>]  250 OK
 
     DataEnd(String),    // 250 2.0.0 Ok: queued as <message_id>
 }
@@ -40,25 +87,92 @@ pub enum SMTPResponse {
 // ------- Structs ------- //
 
 
+/// The reverse-path and parameters accepted from a MAIL FROM command
+pub struct MailFrom {
+    pub sender: String,
+    pub smtputf8: bool,
+    pub binarymime: bool,
+    pub ret: Option<String>,
+    pub envid: Option<String>,
+}
+
+
+/// A forward-path accepted from a RCPT TO command, with its DSN parameters (RFC 3461)
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Recipient {
+    pub address: String,
+    pub notify: Option<Vec<String>>,
+    pub orcpt: Option<String>,
+}
+
+
 #[derive(Serialize)]
 pub struct Email {
     timestamp: String,
     pub message_id: String,
     client_address: String,
-    recipients: Vec<String>,
+    recipients: Vec<Recipient>,
     email_content: Vec<u8>,
     sender: String,
+    smtputf8: bool,
+    #[serde(skip)]
+    binarymime: bool,
+    ret: Option<String>,
+    envid: Option<String>,
+    authenticated_user: Option<String>,
+    listener: String,
+    #[serde(skip)]
+    routing_key: Option<String>,
 }
 
 
+/// The JSON published to AMQP and written to the spool directory
 #[derive(Serialize)]
 struct EmailPayload {
     timestamp: String,
     message_id: String,
     client_address: String,
-    recipients: Vec<String>,
-    email_content: String,
+    recipients: Vec<Recipient>,
+    email_content_b64: String,
+    email_content_encoding: &'static str,
+    sender: String,
+    smtputf8: bool,
+    ret: Option<String>,
+    envid: Option<String>,
+    authenticated_user: Option<String>,
+    listener: String,
+}
+
+
+/// A payload read back from the spool directory. Earlier versions saved the content as text under
+/// `email_content` and the recipients as plain addresses, and left out the fields added since.
+#[derive(Deserialize)]
+struct SpooledPayload {
+    timestamp: String,
+    message_id: String,
+    #[serde(default)]
+    client_address: String,
+    recipients: Vec<SpooledRecipient>,
+    email_content_b64: Option<String>,
+    email_content_encoding: Option<String>,
+    email_content: Option<String>,
     sender: String,
+    #[serde(default)]
+    smtputf8: bool,
+    ret: Option<String>,
+    envid: Option<String>,
+    authenticated_user: Option<String>,
+    #[serde(default)]
+    listener: String,
+}
+
+
+/// A recipient of a spooled payload, either a plain address or one with its DSN parameters
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SpooledRecipient {
+    Address(String),
+    Recipient(Recipient),
 }
 
 
@@ -74,6 +188,13 @@ impl Email {
             email_content: Vec::new(),
             client_address: String::new(),
             sender: String::new(),
+            smtputf8: false,
+            binarymime: false,
+            ret: None,
+            envid: None,
+            authenticated_user: None,
+            listener: String::new(),
+            routing_key: None,
         }
     }
 
@@ -81,13 +202,75 @@ impl Email {
         self.email_content.clear();
         self.recipients.clear();
         self.sender.clear();
+        self.smtputf8 = false;
+        self.binarymime = false;
+        self.ret = None;
+        self.envid = None;
+    }
+
+    /// Record the current time as the time the email was received, once its transaction is accepted.
+    /// A session may be open for long, so the time it started says little about each email.
+    pub fn set_received_now(&mut self) {
+        self.timestamp = chrono::Utc::now().to_rfc3339();
+    }
+
+    /// Hand over the completed transaction, leaving a fresh email with a new message ID for the next one.
+    /// The client address from HELO/EHLO, the AUTH identity and the listener belong to the session, so they are carried over.
+    pub fn take(&mut self) -> Email {
+        let mut next = Email::new(uuid::Uuid::new_v4());
+        next.client_address = self.client_address.clone();
+        next.authenticated_user = self.authenticated_user.clone();
+        next.set_listener(self.listener.clone(), self.routing_key.clone());
+        std::mem::replace(self, next)
     }
 
     pub fn set_client_address(&mut self, client_address: String) {
         self.client_address = client_address;
     }
 
-    pub fn add_recipient(&mut self, recipient: String) {
+    /// Record the listener the email was received on, and the routing key it is published with if it has its own
+    pub fn set_listener(&mut self, listener: String, routing_key: Option<String>) {
+        self.listener = listener;
+        self.routing_key = routing_key;
+    }
+
+    pub fn routing_key(&self) -> Option<&str> {
+        self.routing_key.as_deref()
+    }
+
+    pub fn set_authenticated_user(&mut self, authenticated_user: Option<String>) {
+        self.authenticated_user = authenticated_user;
+    }
+
+    pub fn authenticated_user(&self) -> Option<&str> {
+        self.authenticated_user.as_deref()
+    }
+
+    pub fn sender(&self) -> &str {
+        &self.sender
+    }
+
+    pub fn recipient_count(&self) -> usize {
+        self.recipients.len()
+    }
+
+    /// Size of the message content in bytes
+    pub fn size(&self) -> usize {
+        self.email_content.len()
+    }
+
+    pub fn listener(&self) -> &str {
+        &self.listener
+    }
+
+    /// The time the email was received, in seconds since the Unix epoch
+    pub fn unix_timestamp(&self) -> u64 {
+        chrono::DateTime::parse_from_rfc3339(&self.timestamp)
+            .map(|timestamp| timestamp.timestamp().max(0) as u64)
+            .unwrap_or_default()
+    }
+
+    pub fn add_recipient(&mut self, recipient: Recipient) {
         self.recipients.push(recipient);
     }
 
@@ -95,8 +278,49 @@ impl Email {
         self.email_content.extend_from_slice(content);
     }
 
-    pub fn set_sender(&mut self, sender: String) {
-        self.sender = sender;
+    /// Start a mail transaction from an accepted MAIL FROM command
+    pub fn set_mail_from(&mut self, mail_from: MailFrom) {
+        self.sender = mail_from.sender;
+        self.smtputf8 = mail_from.smtputf8;
+        self.binarymime = mail_from.binarymime;
+        self.ret = mail_from.ret;
+        self.envid = mail_from.envid;
+    }
+
+    /// The addresses in the From header of the message
+    pub fn header_from(&self) -> Vec<String> {
+        header_value(&self.email_content, "From")
+            .map(|from| header_addresses(&from))
+            .unwrap_or_default()
+    }
+
+    /// Add the Date, Message-ID and Sender header fields a submitted message is missing (RFC 6409 section 8)
+    pub fn complete_submission_headers(&mut self, server_name: &str) {
+        let mut headers = String::new();
+
+        if !has_header(&self.email_content, "Date") {
+            headers.push_str(format!("Date: {}\r\n", chrono::Utc::now().to_rfc2822()).as_str());
+        }
+        if !has_header(&self.email_content, "Message-ID") {
+            headers.push_str(format!("Message-ID: <{}@{}>\r\n", self.message_id, server_name).as_str());
+        }
+
+        // Sender is only needed when the submitter is not the single author in From (RFC 5322 section 3.6.2)
+        let from = self.header_from();
+        let sole_author = matches!(from.as_slice(), [author] if author.eq_ignore_ascii_case(&self.sender));
+        if !self.sender.is_empty() && !sole_author && !has_header(&self.email_content, "Sender") {
+            headers.push_str(format!("Sender: <{}>\r\n", self.sender).as_str());
+        }
+
+        self.email_content.splice(0..0, headers.into_bytes());
+    }
+
+    pub fn binarymime(&self) -> bool {
+        self.binarymime
+    }
+
+    pub fn smtputf8(&self) -> bool {
+        self.smtputf8
     }
 
     pub fn serialize(&self) -> Vec<u8> {
@@ -106,18 +330,62 @@ impl Email {
             message_id: self.message_id.clone(),
             client_address: self.client_address.clone(),
             recipients: self.recipients.clone(),
-            email_content: String::from_utf8_lossy(&self.email_content).into_owned(),
+            // The raw message is encoded losslessly so consumers get back the exact bytes received
+            email_content_b64: BASE64.encode(&self.email_content),
+            email_content_encoding: "base64",
             sender: self.sender.clone(),
+            smtputf8: self.smtputf8,
+            ret: self.ret.clone(),
+            envid: self.envid.clone(),
+            authenticated_user: self.authenticated_user.clone(),
+            listener: self.listener.clone(),
         };
 
         serde_json::to_vec(&payload).expect("Failed to serialize Email")
     }
 
-    pub fn validate(&self) -> Result<(), &str> {
+    /// Read back an email from a payload saved in the spool directory, by this or an earlier version.
+    /// The routing key is not part of the payload and is left unset.
+    pub fn deserialize(payload: &[u8]) -> Result<Email, String> {
+        let payload: SpooledPayload = serde_json::from_slice(payload).map_err(|e| e.to_string())?;
+        let email_content = match (payload.email_content_b64, payload.email_content) {
+            (Some(encoded), _) => {
+                let encoding = payload.email_content_encoding.unwrap_or_default();
+                if encoding != "base64" {
+                    return Err(format!("Unknown content encoding: {}", encoding));
+                }
+                BASE64.decode(encoded).map_err(|e| e.to_string())?
+            }
+            (None, Some(content)) => content.into_bytes(),
+            (None, None) => return Err("Email content is missing".to_string()),
+        };
+        let recipients = payload.recipients
+            .into_iter()
+            .map(|recipient| match recipient {
+                SpooledRecipient::Address(address) => Recipient { address, notify: None, orcpt: None },
+                SpooledRecipient::Recipient(recipient) => recipient,
+            })
+            .collect();
+
+        Ok(Email {
+            timestamp: payload.timestamp,
+            message_id: payload.message_id,
+            client_address: payload.client_address,
+            recipients,
+            email_content,
+            sender: payload.sender,
+            smtputf8: payload.smtputf8,
+            binarymime: false,
+            ret: payload.ret,
+            envid: payload.envid,
+            authenticated_user: payload.authenticated_user,
+            listener: payload.listener,
+            routing_key: None,
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
         // TODO: Add more validation checks as needed along with some good email validation's
-        if self.sender.is_empty() {
-            return Err("Sender is empty");
-        }
         if self.recipients.is_empty() {
             return Err("Recipients are empty");
         }
@@ -132,7 +400,7 @@ impl Email {
             "Email {{ message_id: {}, sender: {}, recipients: {:?}, content_length: {} bytes }}",
             self.message_id,
             self.sender,
-            self.recipients,
+            self.recipients.iter().map(|r| r.address.as_str()).collect::<Vec<_>>(),
             self.email_content.len()
         )
     }
@@ -160,66 +428,208 @@ impl SMTPCommand {
             SMTPCommand::Noop
         } else if command_upper == "QUIT" {
             SMTPCommand::Quit
+        } else if command_upper == "STARTTLS" {
+            SMTPCommand::StartTls
+        } else if command_upper.starts_with("BDAT ") {
+            SMTPCommand::Bdat
+        } else if command_upper.starts_with("AUTH ") {
+            SMTPCommand::Auth
         } else {
             SMTPCommand::Unknown
         }
     }
+
+    /// Commands that may only appear as the last command of a pipelined group (RFC 2920 section 3.1).
+    /// AUTH is one as well, since the server may answer it with a challenge (RFC 4954 section 4).
+    pub fn is_sync_point(&self) -> bool {
+        matches!(
+            self,
+            SMTPCommand::EHLO | SMTPCommand::HELO | SMTPCommand::Data | SMTPCommand::Quit | SMTPCommand::Noop
+                | SMTPCommand::StartTls | SMTPCommand::Auth
+        )
+    }
 }
 
 
 impl SMTPResponse {
-    pub fn mail_from_response(addr_part: &str, max_email_size: usize) -> (String, bool) {
-        let mut sender = String::new();
-        let mut valid = true;
-        let parts = addr_part.split_whitespace().collect::<Vec<&str>>();
+    /// Parse the MAIL FROM arguments, returning the sender with its parameters or the response to reject the command with
+    pub fn mail_from_response(arg: &str, max_email_size: usize) -> Result<MailFrom, SMTPResponse> {
+        let (path, params) = parse_path(arg).map_err(SMTPResponse::InvalidSender)?;
+        let parameters = parse_parameters(params).map_err(SMTPResponse::InvalidParameter)?;
+
+        let mut smtputf8 = false;
+        let mut binarymime = false;
+        let mut ret = None;
+        let mut envid = None;
+
+        for Parameter { keyword, value } in parameters {
+            match (keyword.as_str(), value) {
+                ("SIZE", Some(size)) => {
+                    let size = size
+                        .parse::<usize>()
+                        .map_err(|_| SMTPResponse::InvalidParameter("Invalid SIZE parameter"))?;
+                    if size >= max_email_size {
+                        return Err(SMTPResponse::SizeExceeded);
+                    }
+                }
+                ("BODY", Some(body)) => match body.to_ascii_uppercase().as_str() {
+                    "7BIT" | "8BITMIME" => binarymime = false,
+                    "BINARYMIME" => binarymime = true,
+                    _ => return Err(SMTPResponse::InvalidParameter("Invalid BODY parameter")),
+                },
+                ("SMTPUTF8", None) => smtputf8 = true,
+                // The AUTH identity of the original submitter (RFC 4954 section 5) is not relayed, so it is ignored
+                ("AUTH", Some(_)) => {}
+                ("RET", Some(value)) => {
+                    // DSN: return the full message or only its headers
+                    let value = value.to_ascii_uppercase();
+                    if value != "FULL" && value != "HDRS" {
+                        return Err(SMTPResponse::InvalidParameter("Invalid RET parameter"));
+                    }
+                    ret = Some(value);
+                }
+                ("ENVID", Some(value)) => {
+                    // DSN: envelope identifier, at most 100 characters once decoded (RFC 3461 section 4.4)
+                    let value = decode_xtext(value).map_err(SMTPResponse::InvalidParameter)?;
+                    if value.len() > 100 {
+                        return Err(SMTPResponse::InvalidParameter("Invalid ENVID parameter"));
+                    }
+                    envid = Some(value);
+                }
+                ("SIZE" | "BODY" | "RET" | "ENVID" | "AUTH", None) => {
+                    return Err(SMTPResponse::InvalidParameter("Parameter requires a value"));
+                }
+                ("SMTPUTF8", Some(_)) => {
+                    return Err(SMTPResponse::InvalidParameter("Parameter does not take a value"));
+                }
+                _ => return Err(SMTPResponse::UnknownParameter),
+            }
+        }
 
-        for part in parts {
-            let upper = part.to_uppercase();
+        let sender = match path {
+            // The null reverse-path is passed on as is
+            Path::Null => String::new(),
+            Path::Postmaster => return Err(SMTPResponse::InvalidSender("Sender must include a domain")),
+            Path::Mailbox(mailbox) => Self::normalise_mailbox(mailbox, smtputf8)?,
+        };
 
-            if upper.starts_with("SIZE=") {
-                if let Ok(size) = part[5..].parse::<usize>() {
-                    if size >= max_email_size {
-                        valid = false;
+        Ok(MailFrom { sender, smtputf8, binarymime, ret, envid })
+    }
+
+
+    /// Parse the BDAT arguments into the chunk size and whether it is the LAST chunk (RFC 3030)
+    pub fn bdat_response(arg: &str) -> Result<(usize, bool), SMTPResponse> {
+        let mut parts = arg.split_whitespace();
+
+        let size = parts
+            .next()
+            .and_then(|size| size.parse::<usize>().ok())
+            .ok_or(SMTPResponse::InvalidBdat)?;
+
+        let last = match parts.next() {
+            None => false,
+            Some(last) if last.eq_ignore_ascii_case("LAST") => true,
+            Some(_) => return Err(SMTPResponse::InvalidBdat),
+        };
+
+        if parts.next().is_some() {
+            return Err(SMTPResponse::InvalidBdat);
+        }
+
+        Ok((size, last))
+    }
+
+
+    /// Parse the RCPT TO arguments, returning the normalised recipient with its DSN parameters
+    /// or the response to reject it with
+    pub fn rcpt_to_response(arg: &str, smtputf8: bool, server_name: &str) -> Result<Recipient, SMTPResponse> {
+        let (path, params) = parse_path(arg).map_err(SMTPResponse::InvalidRecipient)?;
+        let parameters = parse_parameters(params).map_err(SMTPResponse::InvalidParameter)?;
+
+        let mut notify = None;
+        let mut orcpt = None;
+
+        for Parameter { keyword, value } in parameters {
+            match (keyword.as_str(), value) {
+                ("NOTIFY", Some(value)) => {
+                    // DSN: either NEVER, or any combination of SUCCESS, FAILURE and DELAY
+                    let values = value.split(',').map(str::to_ascii_uppercase).collect::<Vec<_>>();
+                    let valid = match values.as_slice() {
+                        [never] if never == "NEVER" => true,
+                        values => values.iter().enumerate().all(|(i, v)| {
+                            matches!(v.as_str(), "SUCCESS" | "FAILURE" | "DELAY") && !values[..i].contains(v)
+                        }),
+                    };
+                    if !valid {
+                        return Err(SMTPResponse::InvalidParameter("Invalid NOTIFY parameter"));
                     }
+                    notify = Some(values);
                 }
-            } else if upper.starts_with("BODY=") {
-                let body = upper.trim_start_matches("BODY=");
-                if body != "7BIT" && body != "8BITMIME" {
-                    valid = false;
-                    break;
+                ("ORCPT", Some(value)) => {
+                    // DSN: original recipient as <addr-type>;<xtext address>
+                    let (addr_type, value) = value
+                        .split_once(';')
+                        .ok_or(SMTPResponse::InvalidParameter("Invalid ORCPT parameter"))?;
+                    let value = decode_xtext(value).map_err(SMTPResponse::InvalidParameter)?;
+                    if addr_type.is_empty() || value.is_empty() || !addr_type.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+                        return Err(SMTPResponse::InvalidParameter("Invalid ORCPT parameter"));
+                    }
+                    orcpt = Some(format!("{};{}", addr_type.to_lowercase(), value));
                 }
-            } else if part.starts_with('<') && part.ends_with('>') {
-                sender = part[1..part.len()-1].to_string();
-            } else if part.contains('@') {
-                // In some cases, the address may be specified without angle brackets
-                sender = part.to_string();
-            } else {
-                // Invalid address format
-                log::warn!("Invalid MAIL FROM address format: {}", addr_part);
-                valid = false;
-                break;
+                ("NOTIFY" | "ORCPT", None) => {
+                    return Err(SMTPResponse::InvalidParameter("Parameter requires a value"));
+                }
+                _ => return Err(SMTPResponse::UnknownParameter),
             }
         }
 
-        (sender, valid)
+        let address = match path {
+            Path::Null => return Err(SMTPResponse::InvalidRecipient("Null path is not a valid recipient")),
+            // RFC 5321 section 4.5.1 requires <Postmaster> to be accepted without a domain
+            Path::Postmaster => format!("postmaster@{}", server_name),
+            Path::Mailbox(mailbox) => Self::normalise_mailbox(mailbox, smtputf8)?,
+        };
+
+        Ok(Recipient { address, notify, orcpt })
+    }
+
+
+    /// Render a mailbox with its domain normalised. With SMTPUTF8 the address is kept in U-labels,
+    /// otherwise it must have been sent as plain ASCII and is kept in A-labels (RFC 6531).
+    fn normalise_mailbox(mailbox: Mailbox, smtputf8: bool) -> Result<String, SMTPResponse> {
+        if smtputf8 {
+            Ok(mailbox.to_unicode())
+        } else if !mailbox.is_ascii() {
+            Err(SMTPResponse::Utf8NotPermitted)
+        } else {
+            Ok(mailbox.to_ascii())
+        }
     }
 
 
-    fn ehlo_response() -> Vec<u8> {
+    fn ehlo_response(server_name: &str, starttls: bool, auth: Option<String>, max_size: usize) -> Vec<u8> {
         // Note that the last response should not have "-" at the beginning
         // But the top level responses should
         // Example 1: [250 OK] (that is end)
         // Example 2: [250-TEST  250-SIZE  250-PARAMETER  250 EndCMD] (as you can see end will not have "-" at the beginning)
-        let mut response = format!("250-{}\r\n", SERVER_NAME.as_str());
+        let mut response = format!("250-{}\r\n", server_name);
 
-        response.push_str(format!("250-SIZE {}\r\n", *MAX_EMAIL_SIZE_BYTES).as_str());
+        response.push_str(format!("250-SIZE {}\r\n", max_size).as_str());
         response.push_str("250-8BITMIME\r\n");
-        // response.push_str("250-PIPELINING\r\n");
-        // response.push_str("250-ENHANCEDSTATUSCODES\r\n");
-        // response.push_str("250 STARTTLS\r\n");
-        // response.push_str("250-SMTPUTF8\r\n");
-        // response.push_str("250 CHUNKING\r\n");
-        // response.push_str("250 DSN\r\n");
+        response.push_str("250-PIPELINING\r\n");
+        response.push_str("250-ENHANCEDSTATUSCODES\r\n");
+        response.push_str("250-SMTPUTF8\r\n");
+        response.push_str("250-CHUNKING\r\n");
+        response.push_str("250-BINARYMIME\r\n");
+        response.push_str("250-DSN\r\n");
+        // STARTTLS is only offered when TLS is configured and the session is not yet secured
+        if starttls {
+            response.push_str("250-STARTTLS\r\n");
+        }
+        // AUTH is only offered once the session is secured, so passwords are never sent in plaintext
+        if let Some(mechanisms) = auth {
+            response.push_str(format!("250-AUTH {}\r\n", mechanisms).as_str());
+        }
         // response.push_str("250 VRFY\r\n");
         // response.push_str("250 ETRN\r\n");
         response.push_str("250 OK\r\n");
@@ -228,17 +638,213 @@ impl SMTPResponse {
     }
 
 
-    pub fn into_bytes(&self) -> Vec<u8> {
+    /// Basic reply code, RFC 3463 enhanced status code and text of the response.
+    /// The greeting, HELO and EHLO replies never carry an enhanced code (RFC 2034 section 3).
+    fn parts(&self, server_name: &str) -> (u16, Option<&'static str>, String) {
         match self {
-            SMTPResponse::Ok => b"250 OK\r\n".to_vec(),
-            SMTPResponse::Bye => b"221 Bye\r\n".to_vec(),
-            SMTPResponse::Data => b"354 End data with <CR><LF>.<CR><LF>\r\n".to_vec(),
-            SMTPResponse::NotImplemented => b"502 Command not implemented\r\n".to_vec(),
-            SMTPResponse::SizeExceeded => b"552 Message size exceeds fixed maximum message size\r\n".to_vec(),
-            SMTPResponse::Greet => format!("220 {} LSMTP Server (Rust)\r\n", SERVER_NAME.as_str()).into_bytes(),
-            SMTPResponse::Helo => format!("250 {}\r\n", SERVER_NAME.as_str()).into_bytes(),
-            SMTPResponse::Ehlo => Self::ehlo_response(),
-            SMTPResponse::DataEnd(message_id) => format!("250 Ok: queued as {}\r\n", message_id).into_bytes(),
+            SMTPResponse::Ok => (250, Some("2.0.0"), "OK".to_string()),
+            SMTPResponse::Bye => (221, Some("2.0.0"), "Bye".to_string()),
+            SMTPResponse::Data => (354, None, "End data with <CR><LF>.<CR><LF>".to_string()),
+            SMTPResponse::NotImplemented => (502, Some("5.5.1"), "Command not implemented".to_string()),
+            SMTPResponse::SizeExceeded => (552, Some("5.3.4"), "Message size exceeds fixed maximum message size".to_string()),
+            SMTPResponse::BadSequence => (503, Some("5.5.1"), "Bad sequence of commands".to_string()),
+            SMTPResponse::TransactionFailed => (554, Some("5.6.0"), "Transaction failed".to_string()),
+            SMTPResponse::NoValidRecipients => (554, Some("5.5.1"), "No valid recipients".to_string()),
+            SMTPResponse::LocalError => (451, Some("4.3.0"), "Requested action aborted: local error in processing".to_string()),
+            SMTPResponse::SyncError => (554, Some("5.5.1"), "SMTP synchronization error".to_string()),
+            SMTPResponse::Timeout => (421, Some("4.4.2"), format!("{} Timeout exceeded, closing connection", server_name)),
+            SMTPResponse::LineTooLong => (500, Some("5.5.2"), "Line too long".to_string()),
+            SMTPResponse::InvalidUtf8 => (500, Some("5.5.2"), "Syntax error, command is not valid UTF-8".to_string()),
+            SMTPResponse::InvalidSender(reason) => (501, Some("5.1.7"), reason.to_string()),
+            SMTPResponse::InvalidRecipient(reason) => (501, Some("5.1.3"), reason.to_string()),
+            SMTPResponse::UnknownParameter => (555, Some("5.5.4"), "MAIL FROM/RCPT TO parameters not recognized or not implemented".to_string()),
+            SMTPResponse::InvalidParameter(reason) => (501, Some("5.5.4"), reason.to_string()),
+            SMTPResponse::Utf8NotPermitted => (553, Some("5.6.7"), "Non-ASCII addresses require SMTPUTF8".to_string()),
+            SMTPResponse::NotAuthorized(reason) => (550, Some("5.7.1"), reason.to_string()),
+            SMTPResponse::InvalidBdat => (501, Some("5.5.4"), "Syntax error in BDAT parameters".to_string()),
+            SMTPResponse::ChunkReceived(size) => (250, Some("2.0.0"), format!("{} octets received", size)),
+            SMTPResponse::TooManyErrors => (421, Some("4.7.0"), format!("{} Too many errors, closing connection", server_name)),
+            SMTPResponse::StartTls => (220, Some("2.0.0"), "Ready to start TLS".to_string()),
+            SMTPResponse::TlsNotAvailable => (454, Some("4.7.0"), "TLS not available due to temporary reason".to_string()),
+            SMTPResponse::AuthChallenge(challenge) => (334, None, challenge.to_string()),
+            SMTPResponse::AuthSuccess => (235, Some("2.7.0"), "Authentication successful".to_string()),
+            SMTPResponse::AuthFailed => (535, Some("5.7.8"), "Authentication credentials invalid".to_string()),
+            SMTPResponse::AuthCancelled => (501, Some("5.0.0"), "Authentication cancelled".to_string()),
+            SMTPResponse::AuthInvalidResponse => (501, Some("5.5.2"), "Cannot Base64-decode client response".to_string()),
+            SMTPResponse::AuthUnsupported => (504, Some("5.5.4"), "Unrecognized authentication type".to_string()),
+            SMTPResponse::AuthRequired => (530, Some("5.7.0"), "Authentication required".to_string()),
+            SMTPResponse::EncryptionRequired => (538, Some("5.7.11"), "Encryption required for requested authentication mechanism".to_string()),
+            SMTPResponse::Greet(banner) => (220, None, format!("{} {}", server_name, banner)),
+            SMTPResponse::Helo => (250, None, server_name.to_string()),
+            SMTPResponse::Ehlo { .. } => (250, None, server_name.to_string()),
+            SMTPResponse::DataEnd(message_id) => (250, Some("2.0.0"), format!("Ok: queued as {}", message_id)),
         }
     }
+
+
+    /// Render the response on behalf of the server name, including the enhanced status code
+    /// only once ENHANCEDSTATUSCODES was advertised
+    pub fn into_bytes(self, server_name: &str, enhanced: bool) -> Vec<u8> {
+        if let SMTPResponse::Ehlo { starttls, auth, max_size } = self {
+            return Self::ehlo_response(server_name, starttls, auth, max_size);
+        }
+
+        let (code, enhanced_code, text) = self.parts(server_name);
+        match enhanced_code {
+            Some(enhanced_code) if enhanced => format!("{} {} {}\r\n", code, enhanced_code, text).into_bytes(),
+            _ => format!("{} {}\r\n", code, text).into_bytes(),
+        }
+    }
+}
+
+
+// ------- Tests ------- //
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_SIZE: usize = 1000;
+
+    fn rcpt(arg: &str) -> Result<Recipient, SMTPResponse> {
+        SMTPResponse::rcpt_to_response(arg, false, "mx.example.com")
+    }
+
+    #[test]
+    fn rcpt_paths() {
+        assert_eq!(rcpt("<Postmaster>").ok().unwrap().address, "postmaster@mx.example.com");
+        assert!(matches!(rcpt("<>"), Err(SMTPResponse::InvalidRecipient(_))));
+        assert!(matches!(rcpt("<user@example.com> SIZE=10"), Err(SMTPResponse::UnknownParameter)));
+        assert!(matches!(rcpt("<user@example.com> NOTIFY=NEVER notify=NEVER"), Err(SMTPResponse::InvalidParameter(_))));
+
+        // Non-ASCII addresses need SMTPUTF8 and are then kept in U-labels
+        assert!(matches!(rcpt("<user@bücher.de>"), Err(SMTPResponse::Utf8NotPermitted)));
+        assert_eq!(rcpt("<user@xn--bcher-kva.de>").ok().unwrap().address, "user@xn--bcher-kva.de");
+        let recipient = SMTPResponse::rcpt_to_response("<user@xn--bcher-kva.de>", true, "mx.example.com").ok().unwrap();
+        assert_eq!(recipient.address, "user@bücher.de");
+    }
+
+    #[test]
+    fn rcpt_notify() {
+        let recipient = rcpt("<user@example.com> NOTIFY=success,Delay").ok().unwrap();
+        assert_eq!(recipient.notify, Some(vec!["SUCCESS".to_string(), "DELAY".to_string()]));
+        assert_eq!(rcpt("<user@example.com> NOTIFY=NEVER").ok().unwrap().notify, Some(vec!["NEVER".to_string()]));
+
+        assert!(matches!(rcpt("<user@example.com> NOTIFY=NEVER,SUCCESS"), Err(SMTPResponse::InvalidParameter(_))));
+        assert!(matches!(rcpt("<user@example.com> NOTIFY=SUCCESS,SUCCESS"), Err(SMTPResponse::InvalidParameter(_))));
+        assert!(matches!(rcpt("<user@example.com> NOTIFY=SOMETIMES"), Err(SMTPResponse::InvalidParameter(_))));
+        assert!(matches!(rcpt("<user@example.com> NOTIFY"), Err(SMTPResponse::InvalidParameter(_))));
+    }
+
+    #[test]
+    fn rcpt_orcpt() {
+        let recipient = rcpt("<user@example.com> ORCPT=RFC822;user+40example.com").ok().unwrap();
+        assert_eq!(recipient.orcpt.as_deref(), Some("rfc822;user@example.com"));
+
+        assert!(matches!(rcpt("<user@example.com> ORCPT=user@example.com"), Err(SMTPResponse::InvalidParameter(_))));
+        assert!(matches!(rcpt("<user@example.com> ORCPT=rfc822;a+zz"), Err(SMTPResponse::InvalidParameter(_))));
+        assert!(matches!(rcpt("<user@example.com> ORCPT=;user"), Err(SMTPResponse::InvalidParameter(_))));
+    }
+
+    #[test]
+    fn mail_from_dsn() {
+        let mail_from = SMTPResponse::mail_from_response("<user@example.com> RET=hdrs ENVID=QQ+2B1", MAX_SIZE).ok().unwrap();
+        assert_eq!(mail_from.ret.as_deref(), Some("HDRS"));
+        assert_eq!(mail_from.envid.as_deref(), Some("QQ+1"));
+
+        let long_envid = format!("<user@example.com> ENVID={}", "a".repeat(101));
+        assert!(matches!(SMTPResponse::mail_from_response(&long_envid, MAX_SIZE), Err(SMTPResponse::InvalidParameter(_))));
+        assert!(matches!(SMTPResponse::mail_from_response("<user@example.com> ENVID=a+4", MAX_SIZE), Err(SMTPResponse::InvalidParameter(_))));
+        assert!(matches!(SMTPResponse::mail_from_response("<user@example.com> RET=BODY", MAX_SIZE), Err(SMTPResponse::InvalidParameter(_))));
+    }
+
+    #[test]
+    fn spooled_payloads() {
+        let mut email = Email::new(uuid::Uuid::new_v4());
+        email.set_mail_from(SMTPResponse::mail_from_response("<user@example.com> RET=FULL", MAX_SIZE).ok().unwrap());
+        email.add_recipient(rcpt("<rcpt@example.com> NOTIFY=NEVER").ok().unwrap());
+        email.add_content(b"Subject: hi\r\n\r\n\xff\r\n");
+        email.set_listener("mx".to_string(), Some("inbound".to_string()));
+
+        let read = Email::deserialize(&email.serialize()).unwrap();
+        assert_eq!(read.serialize(), email.serialize());
+        assert_eq!(read.routing_key(), None);
+
+        // Payloads saved before the content was base64 encoded carry it as text, with plain recipient addresses
+        let legacy = br#"{"timestamp":"2024-01-01T00:00:00+00:00","message_id":"id","client_address":"client",
+            "recipients":["rcpt@example.com"],"email_content":"Subject: hi\r\n\r\nbody\r\n","sender":"user@example.com"}"#;
+        let read = Email::deserialize(legacy).unwrap();
+        assert_eq!(read.message_id, "id");
+        assert_eq!(read.sender(), "user@example.com");
+        assert_eq!(read.recipient_count(), 1);
+        assert_eq!(read.email_content, b"Subject: hi\r\n\r\nbody\r\n");
+        assert_eq!(read.unix_timestamp(), 1704067200);
+
+        assert!(Email::deserialize(br#"{"timestamp":"","message_id":"id","recipients":[],"sender":""}"#).is_err());
+        assert!(Email::deserialize(b"not json").is_err());
+    }
+
+    #[test]
+    fn mail_from_parameters() {
+        let mail_from = SMTPResponse::mail_from_response("<> SIZE=999 BODY=BINARYMIME SMTPUTF8", MAX_SIZE).ok().unwrap();
+        assert_eq!(mail_from.sender, "");
+        assert!(mail_from.binarymime && mail_from.smtputf8);
+
+        assert!(matches!(SMTPResponse::mail_from_response("<user@example.com> SIZE=1000", MAX_SIZE), Err(SMTPResponse::SizeExceeded)));
+        assert!(matches!(SMTPResponse::mail_from_response("<user@example.com> SMTPUTF8=yes", MAX_SIZE), Err(SMTPResponse::InvalidParameter(_))));
+        assert!(matches!(SMTPResponse::mail_from_response("<Postmaster>", MAX_SIZE), Err(SMTPResponse::InvalidSender(_))));
+        assert!(matches!(SMTPResponse::mail_from_response("<user@example.com> FOO=1", MAX_SIZE), Err(SMTPResponse::UnknownParameter)));
+    }
+
+    #[test]
+    fn bdat_parameters() {
+        assert!(matches!(SMTPResponse::bdat_response("100"), Ok((100, false))));
+        assert!(matches!(SMTPResponse::bdat_response("0 LAST"), Ok((0, true))));
+        assert!(matches!(SMTPResponse::bdat_response("42 last"), Ok((42, true))));
+        assert!(matches!(SMTPResponse::bdat_response(""), Err(SMTPResponse::InvalidBdat)));
+        assert!(matches!(SMTPResponse::bdat_response("abc"), Err(SMTPResponse::InvalidBdat)));
+        assert!(matches!(SMTPResponse::bdat_response("-1"), Err(SMTPResponse::InvalidBdat)));
+        assert!(matches!(SMTPResponse::bdat_response("10 MORE"), Err(SMTPResponse::InvalidBdat)));
+        assert!(matches!(SMTPResponse::bdat_response("10 LAST x"), Err(SMTPResponse::InvalidBdat)));
+    }
+
+    #[test]
+    fn pipelining_sync_points() {
+        for command in ["EHLO client.example.com", "HELO client.example.com", "DATA", "QUIT", "NOOP", "STARTTLS", "AUTH PLAIN"] {
+            assert!(SMTPCommand::from_str(command).is_sync_point(), "{}", command);
+        }
+        for command in ["MAIL FROM:<user@example.com>", "RCPT TO:<user@example.com>", "RSET", "BDAT 100 LAST"] {
+            assert!(!SMTPCommand::from_str(command).is_sync_point(), "{}", command);
+        }
+    }
+
+    #[test]
+    fn reply_codes() {
+        let server_name = "mx.example.com";
+        assert_eq!(SMTPResponse::Ok.parts(server_name), (250, Some("2.0.0"), "OK".to_string()));
+        assert_eq!(SMTPResponse::BadSequence.parts(server_name), (503, Some("5.5.1"), "Bad sequence of commands".to_string()));
+        assert_eq!(SMTPResponse::SizeExceeded.parts(server_name).0, 552);
+        assert_eq!(SMTPResponse::LocalError.parts(server_name).1, Some("4.3.0"));
+        assert_eq!(SMTPResponse::InvalidSender("Bad sender").parts(server_name), (501, Some("5.1.7"), "Bad sender".to_string()));
+        assert_eq!(SMTPResponse::ChunkReceived(100).parts(server_name).2, "100 octets received");
+        assert_eq!(
+            SMTPResponse::Timeout.parts(server_name),
+            (421, Some("4.4.2"), "mx.example.com Timeout exceeded, closing connection".to_string())
+        );
+
+        // The greeting, HELO, DATA and AUTH challenge replies never carry an enhanced code
+        assert_eq!(SMTPResponse::Greet("ESMTP".to_string()).parts(server_name), (220, None, "mx.example.com ESMTP".to_string()));
+        assert_eq!(SMTPResponse::Helo.parts(server_name).1, None);
+        assert_eq!(SMTPResponse::Data.parts(server_name).1, None);
+        assert_eq!(SMTPResponse::AuthChallenge("").parts(server_name).1, None);
+    }
+
+    #[test]
+    fn enhanced_status_codes() {
+        assert_eq!(SMTPResponse::Ok.into_bytes("mx.example.com", true), b"250 2.0.0 OK\r\n");
+        assert_eq!(SMTPResponse::Ok.into_bytes("mx.example.com", false), b"250 OK\r\n");
+        assert_eq!(SMTPResponse::Data.into_bytes("mx.example.com", true), b"354 End data with <CR><LF>.<CR><LF>\r\n");
+        assert_eq!(SMTPResponse::Helo.into_bytes("mx.example.com", true), b"250 mx.example.com\r\n");
+    }
 }
@@ -0,0 +1,80 @@
+// ------- Parsers ------- //
+
+
+/// The header section of a message, up to but not including the empty line that ends it (RFC 5322 section 2.1)
+fn header_section(content: &[u8]) -> &[u8] {
+    let end = content
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|pos| pos + 2)
+        .unwrap_or(content.len());
+    &content[..end]
+}
+
+
+/// The unfolded value of the first header field with the given name, if the message has one
+pub fn header_value(content: &[u8], name: &str) -> Option<String> {
+    let section = String::from_utf8_lossy(header_section(content));
+    let mut lines = section.split("\r\n").peekable();
+
+    while let Some(line) = lines.next() {
+        let Some((field, value)) = line.split_once(':') else {
+            continue;
+        };
+        if !field.trim_end().eq_ignore_ascii_case(name) {
+            continue;
+        }
+
+        // Continuation lines start with whitespace and belong to the same field
+        let mut value = value.trim().to_string();
+        while let Some(next) = lines.next_if(|l| l.starts_with([' ', '\t'])) {
+            value.push(' ');
+            value.push_str(next.trim());
+        }
+        return Some(value);
+    }
+
+    None
+}
+
+
+/// Returns true if the message has a header field with the given name
+pub fn has_header(content: &[u8], name: &str) -> bool {
+    header_value(content, name).is_some()
+}
+
+
+/// The addresses in an address-list header value such as `"Doe, Jane" <jane@example.com>, bob@example.com`.
+/// Display names, quoted strings and comments are skipped.
+pub fn header_addresses(value: &str) -> Vec<String> {
+    let mut addresses = Vec::new();
+    let mut current = String::new();
+    let mut angle = String::new();
+    let (mut quoted, mut in_angle, mut comment_depth) = (false, false, 0usize);
+
+    let mut push = |current: &mut String, angle: &mut String| {
+        let address = if angle.is_empty() { current.trim() } else { angle.trim() };
+        if !address.is_empty() {
+            addresses.push(address.to_string());
+        }
+        current.clear();
+        angle.clear();
+    };
+
+    for c in value.chars() {
+        match c {
+            '"' if comment_depth == 0 => quoted = !quoted,
+            '(' if !quoted => comment_depth += 1,
+            ')' if !quoted && comment_depth > 0 => comment_depth -= 1,
+            _ if quoted || comment_depth > 0 => {}
+            '<' => in_angle = true,
+            '>' => in_angle = false,
+            ',' if !in_angle => push(&mut current, &mut angle),
+            c if in_angle => angle.push(c),
+            c => current.push(c),
+        }
+    }
+    push(&mut current, &mut angle);
+
+    addresses
+}
@@ -1,2 +1,4 @@
+pub mod address;
 pub mod configs;
 pub mod email;
+pub mod headers;
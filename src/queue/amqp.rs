@@ -1,17 +1,39 @@
-use lapin::{BasicProperties, Connection, ConnectionProperties, options::BasicPublishOptions};
+use lapin::{BasicProperties, Connection, ConnectionProperties, publisher_confirm::Confirmation};
+use lapin::options::{BasicPublishOptions, ConfirmSelectOptions};
+use lapin::types::{AMQPValue, FieldTable};
+use crate::models::email::Email;
+use crate::errors::PublishError;
 use crate::models::configs::AMQPConfig;
 use tokio::time::{sleep, Duration};
 
 
+// ------- Constants ------- //
+
+
+// Persistent delivery mode, so the broker keeps the message across restarts
+const DELIVERY_MODE_PERSISTENT: u8 = 2;
+
+// Delay before retrying a failed connection, doubled after every attempt up to the maximum
+const INITIAL_RETRY_DELAY_SECS: u64 = 1;
+const MAX_RETRY_DELAY_SECS: u64 = 60;
+
+
+// ------- Structs ------- //
+
+
+#[allow(clippy::upper_case_acronyms)]
 pub(super) struct AMQP {
     connection: Connection,
     channel: lapin::Channel,
 }
 
 
+// ------- Implementations ------- //
+
+
 impl AMQP {
-    /// Establish a new AMQP connection and channel
-    async fn connect(config: &AMQPConfig) -> Result<AMQP, lapin::Error> {
+    /// Establish a new AMQP connection and a channel in publisher confirm mode
+    pub(super) async fn connect(config: &AMQPConfig) -> Result<AMQP, lapin::Error> {
         let connection = Connection::connect(
             &config.amqp_url(),
             ConnectionProperties::default(),
@@ -19,23 +41,27 @@ impl AMQP {
         .await?;
 
         let channel = connection.create_channel().await?;
+        channel.confirm_select(ConfirmSelectOptions::default()).await?;
 
         Ok(AMQP { connection, channel })
     }
 
 
-    /// Attempt to establish a new AMQP connection and channel with retries
-    pub async fn try_connect(config: &AMQPConfig) -> Option<AMQP> {
-        for attempt in 1..=5 {
+    /// Keep trying to establish a new AMQP connection and channel, waiting longer after each failed attempt
+    pub(super) async fn connect_with_backoff(config: &AMQPConfig) -> AMQP {
+        let mut delay = INITIAL_RETRY_DELAY_SECS;
+        let mut attempt = 1;
+        loop {
             match Self::connect(config).await {
-                Ok(amqp) => return Some(amqp),
+                Ok(amqp) => return amqp,
                 Err(e) => {
-                    log::error!("AMQP connection attempt {} failed: {}", attempt, e);
-                    sleep(Duration::from_secs(3)).await;
+                    log::error!("AMQP connection attempt {} failed: {}, retrying in {}s", attempt, e, delay);
+                    sleep(Duration::from_secs(delay)).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY_SECS);
+                    attempt += 1;
                 }
             }
         }
-        None // Return None if all attempts fail
     }
 
 
@@ -52,20 +78,49 @@ impl AMQP {
     }
 
 
-    /// Publish a message to the specified exchange and routing key
-    pub async fn publish(&self, config: &AMQPConfig, payload: &[u8]) -> Result<(), lapin::Error> {
+    /// Publish a serialized email to the configured exchange, with the routing key of the listener it arrived on
+    /// if it has one. Succeeds only once the broker has acked it.
+    pub async fn publish(&self, config: &AMQPConfig, email: &Email, payload: &[u8]) -> Result<(), PublishError> {
+        let routing_key = email.routing_key().unwrap_or(&config.routing_key);
         let confirm = self.channel
             .basic_publish(
                 &config.exchange,
-                &config.routing_key,
+                routing_key,
                 BasicPublishOptions::default(),
                 payload,
-                BasicProperties::default(),
+                message_properties(email),
             )
             .await?;
 
-        confirm.await?;
+        match confirm.await? {
+            Confirmation::Ack(_) => Ok(()),
+            Confirmation::Nack(_) => Err(PublishError::Nacked),
+            Confirmation::NotRequested => Err(PublishError::NotConfirmed),
+        }
+    }
+}
+
+
+// ------- Helpers ------- //
 
-        Ok(())
+
+/// Persistent delivery with the message metadata, and the envelope summarised in headers
+/// so consumers and headers exchanges can route and filter without parsing the JSON body
+fn message_properties(email: &Email) -> BasicProperties {
+    let mut headers = FieldTable::default();
+    headers.insert("envelope_sender".into(), AMQPValue::LongString(email.sender().into()));
+    headers.insert("recipient_count".into(), AMQPValue::LongLongInt(email.recipient_count() as i64));
+    headers.insert("size".into(), AMQPValue::LongLongInt(email.size() as i64));
+    headers.insert("listener".into(), AMQPValue::LongString(email.listener().into()));
+    if let Some(user) = email.authenticated_user() {
+        headers.insert("authenticated_user".into(), AMQPValue::LongString(user.into()));
     }
+
+    BasicProperties::default()
+        .with_delivery_mode(DELIVERY_MODE_PERSISTENT)
+        .with_message_id(email.message_id.as_str().into())
+        .with_timestamp(email.unix_timestamp())
+        .with_content_type("application/json; charset=utf-8".into())
+        .with_app_id(env!("CARGO_PKG_NAME").into())
+        .with_headers(headers)
 }
@@ -1,82 +1,230 @@
-use crate::models::configs::AMQPConfig;
-use crate::state::save_local_email;
+use crate::models::configs::{AMQPConfig, BaseConfig};
 use crate::models::email::Email;
-use tokio::sync::mpsc;
+use crate::errors::PublishError;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::{self, Duration, MissedTickBehavior};
+use std::path::PathBuf;
+use std::sync::Arc;
 use self::amqp::AMQP;
 
 
 mod amqp;
+pub mod spool;
 
 
-/// Attempt to reconnect to AMQP, closing any existing connection first
-async fn reconnect(amqp: &mut Option<AMQP>, config: &AMQPConfig) {
-    // If we have an existing connection, close it before reconnecting
-    if let Some(old) = amqp.take() {
-        log::warn!("Closing old AMQP connection for reconnect");
-        old.close().await;
+// ------- Constants ------- //
+
+
+// How often the spool directory is checked for emails to replay while connected
+const SPOOL_REPLAY_INTERVAL_SECS: u64 = 30;
+
+
+// ------- Structs ------- //
+
+
+/// An email handed over to the publisher. When the session waits for it, `stored` is told whether
+/// the email was confirmed by the broker or saved to disk, or was lost. With the durable spool,
+/// `write_ahead` is the copy written before the client was answered.
+pub struct QueuedEmail {
+    pub email: Email,
+    pub stored: Option<oneshot::Sender<bool>>,
+    pub write_ahead: Option<PathBuf>,
+}
+
+
+/// The connection to the broker. Once lost it is re-established by a background task,
+/// so that the publisher can go on saving emails to disk in the meantime.
+#[derive(Default)]
+struct Broker {
+    active: Option<AMQP>,
+    connecting: Option<JoinHandle<AMQP>>,
+}
+
+
+// ------- Implementations ------- //
+
+
+impl Broker {
+    /// Start connecting in the background, unless connected or connecting already
+    fn connect(&mut self, config: &Arc<BaseConfig>) {
+        if self.active.is_none() && self.connecting.is_none() {
+            let config = config.clone();
+            self.connecting = Some(tokio::spawn(async move { AMQP::connect_with_backoff(&config.amqp_details).await }));
+        }
+    }
+
+
+    /// Close the current connection, or give up the current attempt, and connect again in the background
+    async fn reconnect(&mut self, config: &Arc<BaseConfig>) {
+        self.close().await;
+        self.connect(config);
     }
 
-    // Attempt to do a new connection
-    log::warn!("Reconnecting to AMQP");
-    *amqp = AMQP::try_connect(config).await;
+
+    /// The live connection if there is one. A lost connection is dropped and reconnected in the background.
+    fn live(&mut self, config: &Arc<BaseConfig>) -> Option<&AMQP> {
+        if self.active.as_ref().is_some_and(|active| !active.is_connected()) {
+            log::warn!("AMQP connection lost, reconnecting!");
+            self.active = None;
+        }
+        self.connect(config);
+        self.active.as_ref()
+    }
+
+
+    /// Close the connection and stop any background connection attempt
+    async fn close(&mut self) {
+        if let Some(task) = self.connecting.take() {
+            task.abort();
+        }
+        if let Some(old) = self.active.take() {
+            log::warn!("Closing AMQP connection");
+            old.close().await;
+        }
+    }
+}
+
+
+// ------- Helpers ------- //
+
+
+/// Tell a waiting session whether its email was stored. The session may have given up waiting already.
+/// The write-ahead copy is only kept for an email that was not stored although the client was told it is queued,
+/// so that it is recovered on restart. A waiting session answers 451 instead, and the client sends the email again.
+fn acknowledge(stored: Option<oneshot::Sender<bool>>, write_ahead: Option<PathBuf>, saved: bool) {
+    let keep = !saved && stored.is_none();
+    if !keep && let Some(path) = write_ahead {
+        spool::remove_write_ahead(&path);
+    }
+    if let Some(stored) = stored {
+        let _ = stored.send(saved);
+    }
 }
 
 
-/// Ensure we have a live AMQP connection, reconnecting if necessary
-async fn ensure_connection(amqp: &mut Option<AMQP>, cfg: &AMQPConfig) {
-    // If we already have a live connection, do nothing
-    if amqp
-        .as_ref()
-        .is_some_and(|a| a.is_connected())
-    {
-        // Connection is alive, nothing to do
-        return;
+/// Wait for the background connection attempt to finish, or forever when there is none
+async fn finished(connecting: &mut Option<JoinHandle<AMQP>>) -> Result<AMQP, JoinError> {
+    match connecting {
+        Some(task) => task.await,
+        None => std::future::pending().await,
     }
+}
 
-    // If we don't have a live connection, log a warning and attempt to reconnect
-    log::warn!("AMQP connection lost, reconnecting!");
-    reconnect(amqp, cfg).await;
+
+/// Connect to AMQP once and disconnect again, to check the broker is reachable with the configured credentials
+pub async fn check_connection(config: &AMQPConfig) -> Result<(), lapin::Error> {
+    let amqp = AMQP::connect(config).await?;
+    amqp.close().await;
+    Ok(())
 }
 
 
-/// Start the AMQP publisher task and return a sender for sending emails to be published
-pub fn start_amqp_publisher(amqp_config: AMQPConfig) -> mpsc::Sender<Email> {
-    let (tx, mut rx) = mpsc::channel::<Email>(amqp_config.buffer_size);
-    log::info!("Starting AMQP publisher task with buffer size: {}", amqp_config.buffer_size);
+/// Start the AMQP publisher task and return a sender for sending emails to be published.
+/// The publisher follows configuration reloads, reconnecting when the AMQP connection settings change.
+/// While the broker is unreachable emails are saved to disk straight away and the connection is retried in the background.
+pub fn start_amqp_publisher(mut config_rx: watch::Receiver<Arc<BaseConfig>>) -> mpsc::Sender<QueuedEmail> {
+    let mut config = config_rx.borrow_and_update().clone();
+    let buffer_size = config.amqp_details.buffer_size;
+    let (tx, mut rx) = mpsc::channel::<QueuedEmail>(buffer_size);
+    log::info!("Starting AMQP publisher task with buffer size: {}", buffer_size);
 
     tokio::spawn(async move {
-        // connect with retry
-        let mut amqp: Option<AMQP> = AMQP::try_connect(&amqp_config).await;
-        log::debug!("AMQP publisher task started; initial connection: {}", amqp.as_ref().map_or("None".to_string(), |_| "Connected".to_string()));
+        let mut broker = Broker::default();
+        broker.connect(&config);
+
+        // The spool is replayed in batches between new emails, right after connecting and then periodically,
+        // so that emails the broker refused are retried too
+        let mut replay_tick = time::interval(Duration::from_secs(SPOOL_REPLAY_INTERVAL_SECS));
+        replay_tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            let QueuedEmail { email, stored, write_ahead } = tokio::select! {
+                email = rx.recv() => match email {
+                    Some(email) => email,
+                    None => break,
+                },
+                Ok(()) = config_rx.changed() => {
+                    let new_config = config_rx.borrow_and_update().clone();
+                    if new_config.amqp_details.amqp_url() != config.amqp_details.amqp_url() {
+                        log::info!("AMQP connection settings changed, reconnecting");
+                        broker.reconnect(&new_config).await;
+                    }
+                    config = new_config;
+                    continue;
+                }
+                connected = finished(&mut broker.connecting) => {
+                    broker.connecting = None;
+                    match connected {
+                        Ok(active) => {
+                            log::info!("Connected to AMQP");
+                            // Anything spooled while the broker was unreachable can go out now
+                            broker.active = Some(active);
+                            replay_tick.reset_immediately();
+                        }
+                        Err(e) => log::error!("AMQP connection task failed: {}", e),
+                    }
+                    continue;
+                }
+                _ = replay_tick.tick() => {
+                    if let Some(active) = broker.live(&config) {
+                        match spool::replay(active, &config).await {
+                            Ok(true) => replay_tick.reset_immediately(),
+                            Ok(false) => {}
+                            Err(_) => broker.reconnect(&config).await,
+                        }
+                    }
+                    continue;
+                }
+            };
 
-        while let Some(email) = rx.recv().await {
+            let amqp_config = &config.amqp_details;
             let msg_id = &email.message_id;
+
+            // A session that stopped waiting has answered 451 already, so the client will send the email again
+            if stored.as_ref().is_some_and(|stored| stored.is_closed()) {
+                log::warn!("Dropping email {} as its session no longer waits for it", msg_id);
+                acknowledge(stored, write_ahead, false);
+                continue;
+            }
+
             let email_bytes = email.serialize();
             log::debug!("Publishing email to AMQP: {}", msg_id);
 
-            // Ensure we have a live connection
-            ensure_connection(&mut amqp, &amqp_config).await;
-
-            // If we still don't have a connection, save the email locally and continue
-            let Some(active) = &amqp else {
-                save_local_email(msg_id, &email_bytes);
+            // Without a live connection the email is saved locally at once rather than waiting for the broker
+            let Some(active) = broker.live(&config) else {
+                let saved = spool::save_local_email(&config.temp_email_dir, msg_id, email_bytes).await;
+                acknowledge(stored, write_ahead, saved);
                 continue;
             };
 
             // Attempt to publish the email
-            if let Err(e) = active.publish(&amqp_config, &email_bytes).await {
-                log::error!("AMQP publish failed: {:?} for email: {}", e, msg_id);
-
-                // Save the email locally and attempt to reconnect
-                save_local_email(msg_id, &email_bytes);
-                reconnect(&mut amqp, &amqp_config).await;
-            } else {
-                log::trace!("AMQP publish confirmed for email: {}", msg_id);
+            match active.publish(amqp_config, &email, &email_bytes).await {
+                Ok(()) => {
+                    log::trace!("AMQP publish confirmed for email: {}", msg_id);
+                    acknowledge(stored, write_ahead, true);
+                }
+
+                // A nack leaves the channel usable, the broker only refused this message
+                Err(PublishError::Nacked) => {
+                    log::error!("AMQP publish nacked by the broker for email: {}", msg_id);
+                    let saved = spool::save_local_email(&config.temp_email_dir, msg_id, email_bytes).await;
+                    acknowledge(stored, write_ahead, saved);
+                }
+
+                Err(e) => {
+                    log::error!("AMQP publish failed: {} for email: {}", e, msg_id);
+
+                    // Save the email locally and reconnect in the background
+                    let saved = spool::save_local_email(&config.temp_email_dir, msg_id, email_bytes).await;
+                    acknowledge(stored, write_ahead, saved);
+                    broker.reconnect(&config).await;
+                }
             }
         }
 
         // The sender has been closed, exit the publisher task
-        amqp.as_ref().map(|a| a.close());
+        broker.close().await;
 
         log::info!("AMQP publisher exiting; sender closed");
     });
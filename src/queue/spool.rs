@@ -0,0 +1,285 @@
+use crate::models::configs::BaseConfig;
+use crate::models::email::Email;
+use crate::errors::PublishError;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use super::amqp::AMQP;
+
+
+// ------- Constants ------- //
+
+
+// Subdirectory of the spool directory that emails which cannot be replayed are moved to
+const POISON_DIR: &str = "poison";
+
+// Subdirectory of the spool directory holding the write-ahead copies of emails not yet confirmed
+const WRITE_AHEAD_DIR: &str = "incoming";
+
+// Number of nacks after which a spooled email is moved to the poison directory
+const MAX_REPLAY_ATTEMPTS: u32 = 3;
+
+// Spooled emails published in one go, so that new emails are not held up by a large spool
+const REPLAY_BATCH_SIZE: usize = 20;
+
+
+// ------- Structs ------- //
+
+
+/// A spooled email read back for replay, with the payload it is published with
+struct SpooledEmail {
+    path: PathBuf,
+    payload: Vec<u8>,
+    email: Email,
+}
+
+
+// ------- Helpers ------- //
+
+
+/// Publish a batch of the oldest spooled emails, deleting each file once the broker has confirmed it.
+/// Returns whether more emails are waiting, or the error that stopped the replay as the connection is most likely gone.
+/// Only publishing runs on the publisher task, the files are read, moved and deleted on the blocking thread pool.
+pub(super) async fn replay(amqp: &AMQP, config: &BaseConfig) -> Result<bool, PublishError> {
+    let dir = PathBuf::from(&config.temp_email_dir);
+    let (batch, more) = match on_blocking_pool(move || read_batch(&dir)).await {
+        Ok(batch) => batch,
+        Err(e) => {
+            log::error!("Failed to read the spool directory {}: {}", config.temp_email_dir, e);
+            return Ok(false);
+        }
+    };
+
+    for SpooledEmail { path, payload, mut email } in batch {
+        // The routing key is not saved with the email, take it from the listener it arrived on
+        let routing_key = config.listeners
+            .iter()
+            .find(|listener| listener.name == email.listener())
+            .and_then(|listener| listener.routing_key.clone());
+        email.set_listener(email.listener().to_string(), routing_key);
+
+        // The payload is published as it was saved, whichever version wrote it
+        match amqp.publish(&config.amqp_details, &email, &payload).await {
+            Ok(()) => {
+                log::info!("Replayed spooled email: {}", email.message_id);
+                let replayed = path.clone();
+                if let Err(e) = on_blocking_pool(move || std::fs::remove_file(replayed)).await {
+                    log::error!("Failed to remove replayed email {}: {}", path.display(), e);
+                }
+            }
+
+            Err(PublishError::Nacked) => {
+                log::error!("AMQP publish nacked by the broker for spooled email: {}", email.message_id);
+                let dir = PathBuf::from(&config.temp_email_dir);
+                if let Err(e) = on_blocking_pool(move || record_nack(&path, &dir)).await {
+                    log::error!("Failed to record the replay attempt of {}: {}", email.message_id, e);
+                }
+            }
+
+            Err(e) => {
+                log::error!("AMQP publish failed: {} while replaying spooled email: {}", e, email.message_id);
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(more)
+}
+
+
+/// Save an email to the spool directory, where it waits until the broker is reachable again.
+/// Returns true once the email is flushed to disk, which blocks and so runs on the blocking thread pool.
+pub(super) async fn save_local_email(temp_email_dir: &str, message_id: &str, contents: Vec<u8>) -> bool {
+    log::warn!("Saving email to the spool directory until the broker is reachable again: {}/{}.json", temp_email_dir, message_id);
+
+    let (dir, id) = (PathBuf::from(temp_email_dir), message_id.to_string());
+    let written = on_blocking_pool(move || write_durably(&dir, &id, &contents)).await;
+    if let Err(e) = written {
+        log::error!("Failed to save email locally and is totally lost! message_id: {}, error: {}", message_id, e);
+        return false;
+    }
+
+    true
+}
+
+
+/// Write an email to the write-ahead directory and flush it to disk before it is acknowledged to the client,
+/// returning the path to remove once the broker has confirmed it
+pub fn write_ahead(temp_email_dir: &str, message_id: &str, payload: &[u8]) -> std::io::Result<PathBuf> {
+    let dir = Path::new(temp_email_dir).join(WRITE_AHEAD_DIR);
+    std::fs::create_dir_all(&dir)?;
+    write_durably(&dir, message_id, payload)
+}
+
+
+/// Drop the write-ahead copy of an email that has been confirmed by the broker or saved to the spool directory
+pub fn remove_write_ahead(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        log::error!("Failed to remove write-ahead email {}: {}", path.display(), e);
+    }
+}
+
+
+/// Move the write-ahead copies left behind by a crash into the spool directory, where they are replayed
+/// once the broker is reachable. Partial files of writes the crash interrupted are deleted, their emails
+/// were never acknowledged. Must run before any session is accepted.
+pub fn recover_write_ahead(temp_email_dir: &str) -> std::io::Result<usize> {
+    remove_partial_files(Path::new(temp_email_dir))?;
+
+    let dir = Path::new(temp_email_dir).join(WRITE_AHEAD_DIR);
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+    remove_partial_files(&dir)?;
+
+    let mut recovered = 0;
+    for path in spooled_files(&dir)? {
+        if let Some(file_name) = path.file_name() {
+            std::fs::rename(&path, Path::new(temp_email_dir).join(file_name))?;
+            recovered += 1;
+        }
+    }
+    Ok(recovered)
+}
+
+
+/// Write `<message_id>.json` so that it survives a crash. It is written under a temporary name and renamed,
+/// so a crash never leaves a partial email behind, and the rename is only durable once the directory is flushed.
+fn write_durably(dir: &Path, message_id: &str, payload: &[u8]) -> std::io::Result<PathBuf> {
+    let partial = dir.join(format!("{}.tmp", message_id));
+    let path = dir.join(format!("{}.json", message_id));
+    let mut file = std::fs::File::create(&partial)?;
+    file.write_all(payload)?;
+    file.sync_all()?;
+    std::fs::rename(&partial, &path)?;
+
+    std::fs::File::open(dir)?.sync_all()?;
+    Ok(path)
+}
+
+
+/// Read the oldest spooled emails, at most a batch of them, and whether more are waiting.
+/// A file that cannot be parsed will never publish, so it goes straight to the poison directory.
+fn read_batch(dir: &Path) -> std::io::Result<(Vec<SpooledEmail>, bool)> {
+    let files = spooled_files(dir)?;
+    if !files.is_empty() {
+        log::info!("Replaying {} of {} spooled emails from {}", files.len().min(REPLAY_BATCH_SIZE), files.len(), dir.display());
+    }
+
+    let mut batch = Vec::with_capacity(files.len().min(REPLAY_BATCH_SIZE));
+    for path in files.iter().take(REPLAY_BATCH_SIZE) {
+        let payload = match std::fs::read(path) {
+            Ok(payload) => payload,
+            Err(e) => {
+                log::error!("Failed to read spooled email {}: {}", path.display(), e);
+                continue;
+            }
+        };
+
+        match Email::deserialize(&payload) {
+            Ok(email) => batch.push(SpooledEmail { path: path.clone(), payload, email }),
+            Err(e) => {
+                log::error!("Spooled email {} is not a valid payload: {}", path.display(), e);
+                poison(path, dir);
+            }
+        }
+    }
+
+    Ok((batch, files.len() > REPLAY_BATCH_SIZE))
+}
+
+
+/// Count a nack in the file name of a spooled email, moving it to the poison directory once it was refused too often
+fn record_nack(path: &Path, dir: &Path) -> std::io::Result<()> {
+    let (name, attempts) = replay_attempts(path);
+    let attempts = attempts + 1;
+    log::warn!("Spooled email {} was refused {} times", name, attempts);
+
+    if attempts >= MAX_REPLAY_ATTEMPTS {
+        poison(path, dir);
+        return Ok(());
+    }
+    std::fs::rename(path, path.with_file_name(format!("{}.{}.json", name, attempts)))
+}
+
+
+/// Run file system work on the blocking thread pool, so that it does not hold up the publisher
+async fn on_blocking_pool<T: Send + 'static>(work: impl FnOnce() -> std::io::Result<T> + Send + 'static) -> std::io::Result<T> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)))
+}
+
+
+/// Delete the temporary files `write_durably` leaves behind when interrupted before its rename
+fn remove_partial_files(dir: &Path) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "tmp") {
+            log::warn!("Removing partially written email {}", path.display());
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+
+/// The emails in the spool directory, oldest first
+fn spooled_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files: Vec<(SystemTime, PathBuf)> = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if !entry.file_type()?.is_file() || path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let modified = entry.metadata()?.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        files.push((modified, path));
+    }
+
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+
+/// The name of a spooled email and the number of times the broker refused it, which is kept in
+/// the file name as `<name>.<attempts>.json` so that it survives a restart
+fn replay_attempts(path: &Path) -> (String, u32) {
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    match stem.rsplit_once('.').map(|(name, attempts)| (name, attempts.parse::<u32>())) {
+        Some((name, Ok(attempts))) => (name.to_string(), attempts),
+        _ => (stem, 0),
+    }
+}
+
+
+/// Move a spooled email that keeps failing out of the way, so it is not replayed again
+fn poison(path: &Path, dir: &Path) {
+    let poison_dir = dir.join(POISON_DIR);
+    let Some(file_name) = path.file_name() else {
+        return;
+    };
+
+    let moved = std::fs::create_dir_all(&poison_dir)
+        .and_then(|_| std::fs::rename(path, poison_dir.join(file_name)));
+    match moved {
+        Ok(()) => log::error!("Moved spooled email to {}, manual intervention required: {}", poison_dir.display(), file_name.to_string_lossy()),
+        Err(e) => log::error!("Failed to move spooled email {} to {}: {}", path.display(), poison_dir.display(), e),
+    }
+}
+
+
+// ------- Tests ------- //
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attempts_in_file_name() {
+        assert_eq!(replay_attempts(Path::new("/spool/1b4e28ba-2fa1-11d2-883f-0016d3cca427.json")), ("1b4e28ba-2fa1-11d2-883f-0016d3cca427".to_string(), 0));
+        assert_eq!(replay_attempts(Path::new("/spool/1b4e28ba-2fa1-11d2-883f-0016d3cca427.2.json")), ("1b4e28ba-2fa1-11d2-883f-0016d3cca427".to_string(), 2));
+        assert_eq!(replay_attempts(Path::new("/spool/some.name.json")), ("some.name".to_string(), 0));
+    }
+}
@@ -1,83 +1,293 @@
-use crate::models::configs::{BaseConfig, MAX_TIMEOUT_SECS, TEMP_EMAIL_DIR};
+use crate::models::configs::{BaseConfig, ListenerTls};
 use tokio::net::{TcpListener, TcpStream};
+use crate::handler::tls::{load_tls_acceptor, SessionStream, TlsMode};
 use crate::handler::email::EmailHandler;
-use crate::queue::start_amqp_publisher;
-use crate::models::email::Email;
+use crate::queue::{check_connection, spool, start_amqp_publisher, QueuedEmail};
+use crate::handler::policy::ListenerPolicy;
+use crate::auth::ownership::SenderOwnership;
+use crate::auth::Authenticator;
+use crate::errors::{ConfigError, LSMTPError};
+use crate::logging;
+use crate::cli::Cli;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::time;
 
 
+// Time to wait for the AMQP broker when checking the configuration
+const AMQP_CHECK_TIMEOUT_SECS: u64 = 10;
+
+
 // Type alias for the email sender channel
-pub type EmailSender = tokio::sync::mpsc::Sender<Email>;
+pub type EmailSender = tokio::sync::mpsc::Sender<QueuedEmail>;
+
 
+// A listener with the policy applied to every session it accepts, replaced when the configuration is reloaded
+pub type Listener = (TcpListener, watch::Receiver<Arc<ListenerPolicy>>);
 
-/// Initializes the Logging, TCP Listener, and AMQP Publisher for the LSMTP Daemon
-pub async fn init() -> (TcpListener, EmailSender) {
+
+/// Initializes the Logging, TCP Listeners, TLS Acceptor, credentials and AMQP Publisher for the LSMTP Daemon
+pub async fn init(cli: Cli) -> (Vec<Listener>, EmailSender) {
     // Initialize logging
-    env_logger::init();
+    logging::init();
 
-    // Create temporary email storage directory if it doesn't exist
-    std::fs::create_dir_all(TEMP_EMAIL_DIR.as_str()).unwrap();
+    // Load and validate the configuration file and environment, refusing to start on any problem
+    let base_config = match BaseConfig::load(cli.config.as_deref()) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    logging::set_level(cli.log_level.or(base_config.log_level));
 
-    // Preparing to start the server by collecting environment variables
-    let base_config = BaseConfig::from_env();
+    // Emails accepted before a crash but never confirmed by the broker are replayed from the spool
+    match spool::recover_write_ahead(&base_config.temp_email_dir) {
+        Ok(0) => {}
+        Ok(recovered) => log::warn!("Recovered {} unconfirmed emails from the write-ahead spool", recovered),
+        Err(e) => {
+            log::error!("Failed to recover the write-ahead spool in {}: {}", base_config.temp_email_dir, e);
+            std::process::exit(1);
+        }
+    }
 
-    // Initialize the TCP listener
-    let listener = TcpListener::bind(base_config.bind_uri())
-        .await
-        .expect("Failed to bind to address");
+    // Load the TLS certificate, credentials and sender ownership the listener policies depend on
+    let policies = match load_policies(&base_config) {
+        Ok(policies) => policies,
+        Err(e) => {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-    log::info!("LSMTP Daemon started on {}", base_config.bind_uri());
+    // Bind every listener, each with its own policy
+    let mut listeners = Vec::with_capacity(policies.len());
+    let mut policy_txs = Vec::with_capacity(policies.len());
+    for policy in policies {
+        let listener_config = &policy.listener;
+        let listener = match TcpListener::bind(listener_config.bind_uri()).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!("Failed to bind listener {} to {}: {}", listener_config.name, listener_config.bind_uri(), e);
+                std::process::exit(1);
+            }
+        };
 
-    // Initialize the channel
-    let tx = start_amqp_publisher(base_config.amqp_details);
+        log::info!("LSMTP Daemon listener {} ({:?}, TLS {:?}) started on {}", listener_config.name, listener_config.profile, listener_config.tls, listener_config.bind_uri());
 
-    (listener, tx)
-}
+        let (policy_tx, policy_rx) = watch::channel(policy);
+        listeners.push((listener, policy_rx));
+        policy_txs.push(policy_tx);
+    }
 
+    // Initialize the channel
+    let (config_tx, config_rx) = watch::channel(base_config);
+    let tx = start_amqp_publisher(config_rx);
 
-/// Handle a single client connection. This function is spawned as a new task for each connection.
-/// This is the main logic for handling a client connection
-pub async fn handle_connection(socket: TcpStream, addr: SocketAddr, amqp_tx: EmailSender) {
-    // Create a new UUID for the connection/session
-    let conn_id = uuid::Uuid::new_v4();
+    // Swap in a new configuration whenever SIGHUP is received
+    tokio::spawn(reload_on_sighup(cli, policy_txs, config_tx));
 
-    // Create a new email handler
-    let client = EmailHandler::new(socket, conn_id);
+    (listeners, tx)
+}
 
-    log::debug!("[conn={}] Handling connection from: {}", conn_id, addr);
 
-    // Run the client with a timeout
-    match time::timeout(time::Duration::from_secs(*MAX_TIMEOUT_SECS), client.run()).await {
-        Ok(Ok(email)) => {
-            log::info!("[conn={}] Received email: {}", conn_id, email.message_id);
+/// Validate the configuration, the TLS material and credentials it points to, and that the AMQP broker
+/// is reachable, printing every problem found. Returns true if the daemon can start with it.
+pub async fn check_config(cli: &Cli) -> bool {
+    logging::init();
 
-            // Send the email to the AMQP channel
-            if let Err(e) = amqp_tx.send(email).await {
-                log::error!("[conn={}] Failed to send email to AMQP channel: {}", conn_id, e);
-            }
+    let config = match BaseConfig::load(cli.config.as_deref()) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
         }
+    };
+    if let Err(e) = load_policies(&config) {
+        eprintln!("{}", e);
+        return false;
+    }
 
+    let timeout = time::Duration::from_secs(AMQP_CHECK_TIMEOUT_SECS);
+    match time::timeout(timeout, check_connection(&config.amqp_details)).await {
+        Ok(Ok(())) => {}
         Ok(Err(e)) => {
-            log::error!("[conn={}] Error handling client {}: {}", conn_id, addr, e);
+            eprintln!("AMQP broker is not reachable: {}", e);
+            return false;
         }
-
         Err(_) => {
-            log::warn!("[conn={}] Connection handler timed out after {} seconds for client: {}", conn_id, *MAX_TIMEOUT_SECS, addr);
+            eprintln!("AMQP broker did not respond within {} seconds", AMQP_CHECK_TIMEOUT_SECS);
+            return false;
         }
     }
+
+    println!("Configuration is valid, with {} listeners and a reachable AMQP broker", config.listeners.len());
+    true
 }
 
 
-/// Locally save the contents of an email to a temporary directory for later retrieval or manual intervention
-pub fn save_local_email(message_id: &str, contents: &[u8]) {
-    let path = format!("{}/{}.json", TEMP_EMAIL_DIR.as_str(), message_id);
+/// Build the policy of every listener, loading the TLS certificate, credentials and sender ownership they share
+fn load_policies(config: &Arc<BaseConfig>) -> Result<Vec<Arc<ListenerPolicy>>, ConfigError> {
+    // Load the TLS certificate and key if configured, for the listeners offering STARTTLS or implicit TLS
+    let tls_acceptor = match (&config.tls_cert_path, &config.tls_key_path) {
+        (Some(cert), Some(key)) => Some(load_tls_acceptor(cert, key)?),
+        _ => None,
+    };
+
+    // Load the users and OAuth2 keys clients can authenticate against with SMTP AUTH, if configured
+    let authenticator = Authenticator::load(config.auth_users_file.as_deref(), config.oauth.as_ref())?
+        .map(Arc::new);
+    let ownership = config
+        .sender_ownership_file
+        .as_deref()
+        .map(SenderOwnership::load)
+        .transpose()?
+        .unwrap_or_default();
+    let ownership = Arc::new(ownership);
+
+    let policies = config.listeners
+        .iter()
+        .map(|listener_config| {
+            let tls_mode = match (listener_config.tls, &tls_acceptor) {
+                (ListenerTls::StartTls, Some(acceptor)) => TlsMode::StartTls(acceptor.clone()),
+                (ListenerTls::Implicit, Some(acceptor)) => TlsMode::Implicit(acceptor.clone()),
+                _ => TlsMode::Disabled,
+            };
+            Arc::new(ListenerPolicy {
+                config: config.clone(),
+                listener: listener_config.clone(),
+                tls_mode,
+                authenticator: authenticator.clone(),
+                ownership: ownership.clone(),
+            })
+        })
+        .collect();
+
+    Ok(policies)
+}
+
+
+/// Reload the configuration on every SIGHUP. New sessions use the new configuration while running
+/// sessions finish under the one they started with. An invalid configuration is rejected and the current one kept.
+async fn reload_on_sighup(cli: Cli, policy_txs: Vec<watch::Sender<Arc<ListenerPolicy>>>, config_tx: watch::Sender<Arc<BaseConfig>>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            log::error!("Failed to listen for SIGHUP, configuration reload is disabled: {}", e);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        log::info!("Received SIGHUP, reloading configuration");
+        match reload(&cli, &policy_txs, &config_tx) {
+            Ok(()) => log::info!("Configuration reloaded"),
+            Err(e) => log::error!("Configuration reload rejected, keeping the current configuration. {}", e),
+        }
+    }
+}
 
-    // Warn the user that we are using a temporary storage location
-    log::warn!("Saving email to temporary location, manual intervention required: {}", &path);
 
-    // Write the email to the file system
-    if let Err(e) = std::fs::write(&path, contents) {
-        log::error!("Failed to save email locally and is totally lost! path: {}, error: {}", &path, e);
+/// Load, validate and swap in a new configuration
+fn reload(cli: &Cli, policy_txs: &[watch::Sender<Arc<ListenerPolicy>>], config_tx: &watch::Sender<Arc<BaseConfig>>) -> Result<(), ConfigError> {
+    let config = Arc::new(BaseConfig::load(cli.config.as_deref())?);
+    let current = config_tx.borrow().clone();
+
+    // Sockets are only bound at startup, so the listeners must stay as they are
+    let same_listeners = config.listeners.len() == current.listeners.len()
+        && config.listeners
+            .iter()
+            .zip(&current.listeners)
+            .all(|(new, old)| new.name == old.name && new.bind_uri() == old.bind_uri());
+    if !same_listeners {
+        return Err(ConfigError::from("Listeners can not be added, removed, renamed or rebound without a restart".to_string()));
+    }
+    // Emails spooled in the old directory would never be replayed or recovered from the new one
+    if config.temp_email_dir != current.temp_email_dir {
+        return Err(ConfigError::from("temp_email_dir can not be changed without a restart".to_string()));
+    }
+    if config.amqp_details.buffer_size != current.amqp_details.buffer_size {
+        log::warn!("The AMQP buffer size only changes on restart");
+    }
+
+    let policies = load_policies(&config)?;
+
+    // Everything was loaded, so the new configuration can be swapped in
+    for (policy_tx, policy) in policy_txs.iter().zip(policies) {
+        policy_tx.send_replace(policy);
+    }
+    logging::set_level(cli.log_level.or(config.log_level));
+    config_tx.send_replace(config);
+
+    Ok(())
+}
+
+
+/// Accept connections on a listener until it fails, handling each client on its own task
+/// with the policy that is current when the connection is accepted
+pub async fn serve(listener: TcpListener, policy_rx: watch::Receiver<Arc<ListenerPolicy>>, amqp_tx: EmailSender) -> Result<(), LSMTPError> {
+    loop {
+        // Accept all and any incoming connections
+        let (socket, addr) = listener.accept().await?;
+        log::trace!("Incoming connection from: {}", addr);
+
+        // Clone the AMQP sender and listener policy references
+        let amqp_tx = amqp_tx.clone();
+        let policy = policy_rx.borrow().clone();
+
+        // Spawn a new task to handle the client connection
+        tokio::spawn(async move {
+            handle_connection(socket, addr, amqp_tx, policy).await;
+        });
+    }
+}
+
+
+/// Handle a single client connection. This function is spawned as a new task for each connection.
+/// This is the main logic for handling a client connection
+pub async fn handle_connection(socket: TcpStream, addr: SocketAddr, amqp_tx: EmailSender, policy: Arc<ListenerPolicy>) {
+    // Create a new UUID for the connection/session
+    let conn_id = uuid::Uuid::new_v4();
+
+    // With implicit TLS the handshake must complete before the greeting is sent
+    let stream = match &policy.tls_mode {
+        TlsMode::Disabled | TlsMode::StartTls(_) => SessionStream::Plain(socket),
+        TlsMode::Implicit(acceptor) => {
+            let handshake = SessionStream::Plain(socket).upgrade(acceptor);
+            match time::timeout(time::Duration::from_secs(policy.listener.max_timeout_secs), handshake).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    log::warn!("[conn={}] TLS handshake failed for client {}: {}", conn_id, addr, e);
+                    return;
+                }
+                Err(_) => {
+                    log::warn!("[conn={}] TLS handshake timed out for client: {}", conn_id, addr);
+                    return;
+                }
+            }
+        }
+    };
+
+    log::debug!("[conn={}] Handling connection from: {} on listener: {}", conn_id, addr, policy.listener.name);
+
+    // Create a new email handler
+    let max_timeout_secs = policy.listener.max_timeout_secs;
+    let client = EmailHandler::new(stream, conn_id, policy, amqp_tx);
+
+    // Run the client session, every accepted email is published as it completes
+    match client.run().await {
+        Ok(()) => {
+            log::debug!("[conn={}] Connection closed for client: {}", conn_id, addr);
+        }
+
+        Err(LSMTPError::TimeoutError) => {
+            log::warn!("[conn={}] Connection timed out after {} idle seconds for client: {}", conn_id, max_timeout_secs, addr);
+        }
+
+        Err(e) => {
+            log::error!("[conn={}] Error handling client {}: {}", conn_id, addr, e);
+        }
     }
 }